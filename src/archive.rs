@@ -0,0 +1,276 @@
+//! Rolling zip archives as an output sink.
+//!
+//! Outputs are stored uncompressed in `outputs-NNNNN.zip` archives of at most a
+//! given size. The archive being filled is named `….zip.part` and only renamed
+//! once its central directory is written and synced, so every `.zip` in the
+//! output directory is complete and can be read back as an input. The names in
+//! the finished archives' central directories form the index that tells which
+//! outputs already exist. The complete entries of an unfinished `.part` left by
+//! a killed run are copied into a new archive; only the entry being written
+//! when it was killed is rendered again.
+
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use zip::{
+    CompressionMethod, ZipArchive, ZipWriter, read::read_zipfile_from_stream,
+    write::SimpleFileOptions,
+};
+
+/// Local file header and central directory record sizes, without the name.
+const ENTRY_OVERHEAD: u64 = 30 + 46;
+
+/// End of central directory record, with room for its Zip64 variant.
+const END_OVERHEAD: u64 = 22 + 56 + 20;
+
+/// The archive being filled.
+struct Current {
+    writer: ZipWriter<BufWriter<File>>,
+    /// The `.part` path it is written to.
+    path: PathBuf,
+    /// Upper bound of its size once finished.
+    size: u64,
+}
+
+struct State {
+    /// Entry names of every finished archive and of the current one.
+    index: HashSet<String>,
+    current: Option<Current>,
+    /// Number of the next archive to start.
+    next: u32,
+}
+
+/// Rolling zip archives in one directory, shared by the workers of a run.
+pub struct Archives {
+    dir: PathBuf,
+    /// Most bytes per archive; a single larger entry gets an archive of its own.
+    cap: u64,
+    state: Mutex<State>,
+}
+
+fn zip_error(e: zip::result::ZipError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Archive number of a file named `outputs-NNNNN.zip`.
+fn archive_number(name: &str) -> Option<u32> {
+    name.strip_prefix("outputs-")?
+        .strip_suffix(".zip")?
+        .parse()
+        .ok()
+}
+
+impl Archives {
+    /// Open the archives in `dir`, creating it if needed, index the entries of
+    /// finished ones and recover those of unfinished ones.
+    pub fn open(dir: &Path, cap: u64) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let mut index = HashSet::new();
+        let mut next = 1;
+        let mut parts = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            if let Some(n) = name.strip_suffix(".part").and_then(archive_number) {
+                parts.push(path);
+                next = next.max(n + 1);
+            } else if let Some(n) = archive_number(name) {
+                let archive = ZipArchive::new(File::open(&path)?).map_err(zip_error)?;
+                index.extend(archive.file_names().map(str::to_owned));
+                next = next.max(n + 1);
+            }
+        }
+        let archives = Archives {
+            dir: dir.to_owned(),
+            cap,
+            state: Mutex::new(State {
+                index,
+                current: None,
+                next,
+            }),
+        };
+        if !parts.is_empty() {
+            let mut recovered = 0;
+            for part in &parts {
+                recovered += archives.recover(part)?;
+            }
+            // The copies are durable before the originals go.
+            archives.finish()?;
+            for part in &parts {
+                std::fs::remove_file(part)?;
+            }
+            eprintln!(
+                "recovered {recovered} outputs from {} unfinished archive(s)",
+                parts.len()
+            );
+        }
+        Ok(archives)
+    }
+
+    /// Copy the complete entries of the unfinished archive at `path` into the
+    /// current one, returning how many were new. Reading stops at the first
+    /// entry that is cut short, fails its CRC or still has the zero sizes its
+    /// header holds until the entry is done.
+    fn recover(&self, path: &Path) -> io::Result<usize> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut recovered = 0;
+        while let Ok(Some(mut entry)) = read_zipfile_from_stream(&mut reader) {
+            if entry.compressed_size() == 0 {
+                break;
+            }
+            let name = entry.name().to_owned();
+            let mut bytes = Vec::with_capacity(entry.size() as usize);
+            if entry.read_to_end(&mut bytes).is_err() {
+                break;
+            }
+            if self.add(&name, &bytes)?.is_some() {
+                recovered += 1;
+            }
+        }
+        Ok(recovered)
+    }
+
+    /// Whether an entry named `name` was already written.
+    pub fn contains(&self, name: &str) -> bool {
+        self.state.lock().unwrap().index.contains(name)
+    }
+
+    /// Names of every entry written so far.
+    pub fn names(&self) -> Vec<String> {
+        self.state.lock().unwrap().index.iter().cloned().collect()
+    }
+
+    /// Store `bytes` as entry `name`, starting a new archive first if the
+    /// current one would outgrow the cap. Returns the path of the entry as
+    /// `<archive>.zip/<name>`, or `None` if the entry already exists.
+    pub fn add(&self, name: &str, bytes: &[u8]) -> io::Result<Option<PathBuf>> {
+        let size = ENTRY_OVERHEAD + 2 * name.len() as u64 + bytes.len() as u64;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.index.contains(name) {
+                return Ok(None);
+            }
+            if let Some(current) = &state.current
+                && current.size + size > self.cap
+            {
+                // Finishing syncs the whole archive; let the other workers
+                // start the next one meanwhile.
+                let full = state.current.take().unwrap();
+                drop(state);
+                finish(full, &self.dir)?;
+                state = self.state.lock().unwrap();
+            } else {
+                break;
+            }
+        }
+        if state.current.is_none() {
+            let path = self.dir.join(format!("outputs-{:05}.zip.part", state.next));
+            state.next += 1;
+            let file = File::create_new(&path)?;
+            state.current = Some(Current {
+                writer: ZipWriter::new(BufWriter::new(file)),
+                path,
+                size: END_OVERHEAD,
+            });
+        }
+        let current = state.current.as_mut().unwrap();
+        let options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Stored)
+            .large_file(bytes.len() as u64 >= u32::MAX as u64);
+        current
+            .writer
+            .start_file(name, options)
+            .map_err(zip_error)?;
+        // Flush so a failure (e.g. a full disk) shows up here, then drop the
+        // partial entry so finishing the archive can't record it as complete.
+        let written = current
+            .writer
+            .write_all(bytes)
+            .and_then(|()| current.writer.flush());
+        if let Err(e) = written {
+            current.writer.abort_file().map_err(zip_error)?;
+            return Err(e);
+        }
+        current.size += size;
+        let entry = current.path.with_extension("").join(name);
+        state.index.insert(name.to_owned());
+        Ok(Some(entry))
+    }
+
+    /// Finish the archive being filled, if any.
+    pub fn finish(&self) -> io::Result<()> {
+        match self.state.lock().unwrap().current.take() {
+            Some(current) => finish(current, &self.dir),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Write the central directory of `current`, sync it, drop the `.part`
+/// suffix and sync `dir` so the rename is durable too.
+fn finish(current: Current, dir: &Path) -> io::Result<()> {
+    let file = current
+        .writer
+        .finish()
+        .map_err(zip_error)?
+        .into_inner()
+        .map_err(io::IntoInnerError::into_error)?;
+    file.sync_all()?;
+    std::fs::rename(&current.path, current.path.with_extension(""))?;
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes of an archive holding `names` as left by a run killed while
+    /// writing the last of them.
+    fn killed(names: &[&str]) -> Vec<u8> {
+        let dir = tempfile::tempdir().unwrap();
+        let archives = Archives::open(dir.path(), 1 << 20).unwrap();
+        for name in names {
+            archives.add(name, name.repeat(100).as_bytes()).unwrap();
+        }
+        std::fs::read(dir.path().join("outputs-00001.zip.part")).unwrap()
+    }
+
+    fn entries(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn unfinished_archives_are_recovered() {
+        let bytes = killed(&["a", "b", "c"]);
+        // Whole, cut inside `b`, and cut inside the first header.
+        for (len, expected) in [(bytes.len(), 2), (bytes.len() / 2, 1), (10, 0)] {
+            let dir = tempfile::tempdir().unwrap();
+            std::fs::write(dir.path().join("outputs-00003.zip.part"), &bytes[..len]).unwrap();
+            let archives = Archives::open(dir.path(), 1 << 20).unwrap();
+            // `c` was still being written, so its header has no sizes yet.
+            let mut names = archives.names();
+            names.sort();
+            assert_eq!(names, ["a", "b"][..expected]);
+            let archive = match expected {
+                0 => vec![],
+                _ => vec!["outputs-00004.zip".to_owned()],
+            };
+            assert_eq!(entries(dir.path()), archive);
+            drop(archives);
+            let reopened = Archives::open(dir.path(), 1 << 20).unwrap();
+            assert_eq!(reopened.names().len(), expected);
+        }
+    }
+}
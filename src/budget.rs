@@ -0,0 +1,299 @@
+//! Run budgets and quotas.
+//!
+//! Every limit is enforced exactly: an output is encoded in memory first, and
+//! its file count, bytes and quota slots are reserved under one lock before it
+//! is written. A failed write gives its reservation back.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::Mode;
+use crate::stop::Reason;
+
+/// Limits on what a run may produce.
+#[derive(Default)]
+pub struct Limits {
+    pub max_bytes: Option<u64>,
+    pub max_files: Option<u64>,
+    pub max_time: Option<Duration>,
+    /// Most outputs per mode.
+    pub mode_quotas: Vec<(Mode, u64)>,
+    /// Most outputs per number of inputs.
+    pub n_quotas: Vec<(usize, u64)>,
+}
+
+/// Usage so far.
+#[derive(Default)]
+struct Used {
+    bytes: u64,
+    files: u64,
+    /// Files whose reservation was committed.
+    written: u64,
+    /// Parallel to `Limits::mode_quotas`.
+    modes: Vec<u64>,
+    /// Parallel to `Limits::n_quotas`.
+    ns: Vec<u64>,
+}
+
+/// Budgets shared by the workers of a run.
+pub struct Budgets {
+    limits: Limits,
+    deadline: Option<Instant>,
+    used: Mutex<Used>,
+}
+
+/// Why an output could not be reserved.
+pub enum Refusal {
+    /// A run-wide budget is used up; the run should stop.
+    Exhausted(Reason),
+    /// The quota for this output's mode or input count is full.
+    Quota,
+}
+
+/// Budget taken by one output until it is committed; dropping it uncommitted
+/// gives the budget back.
+pub struct Reservation<'b> {
+    budgets: &'b Budgets,
+    mode: Option<usize>,
+    n: Option<usize>,
+    bytes: u64,
+    committed: bool,
+}
+
+impl Budgets {
+    /// Budgets for a run starting now.
+    pub fn new(limits: Limits) -> Self {
+        let used = Used {
+            modes: vec![0; limits.mode_quotas.len()],
+            ns: vec![0; limits.n_quotas.len()],
+            ..Used::default()
+        };
+        Budgets {
+            deadline: limits.max_time.map(|t| Instant::now() + t),
+            limits,
+            used: Mutex::new(used),
+        }
+    }
+
+    /// The size budget in bytes, if any.
+    pub fn max_bytes(&self) -> Option<u64> {
+        self.limits.max_bytes
+    }
+
+    /// Whether the time budget has run out.
+    pub fn timed_out(&self) -> bool {
+        self.deadline.is_some_and(|d| Instant::now() >= d)
+    }
+
+    fn mode_slot(&self, mode: Mode) -> Option<usize> {
+        self.limits.mode_quotas.iter().position(|&(m, _)| m == mode)
+    }
+
+    fn n_slot(&self, n: usize) -> Option<usize> {
+        self.limits.n_quotas.iter().position(|&(q, _)| q == n)
+    }
+
+    /// Whether an output of this mode and input count could still be kept, so
+    /// merges that could not are skipped before rendering.
+    pub fn admits(&self, mode: Mode, n: usize) -> bool {
+        let used = self.used.lock().unwrap();
+        let mode_ok = self
+            .mode_slot(mode)
+            .is_none_or(|i| used.modes[i] < self.limits.mode_quotas[i].1);
+        let n_ok = self
+            .n_slot(n)
+            .is_none_or(|i| used.ns[i] < self.limits.n_quotas[i].1);
+        mode_ok && n_ok
+    }
+
+    /// Whether no output of any of `modes` and input counts `ns` could still
+    /// be kept, so the run has nothing left to do.
+    pub fn all_full(&self, modes: &[Mode], ns: &[usize]) -> bool {
+        ns.iter()
+            .all(|&n| modes.iter().all(|&mode| !self.admits(mode, n)))
+    }
+
+    /// Whether committed outputs have used up the file budget.
+    pub fn files_full(&self) -> bool {
+        self.limits
+            .max_files
+            .is_some_and(|max| self.used.lock().unwrap().written >= max)
+    }
+
+    /// Whether the quota for `n`-input merges is full.
+    pub fn n_full(&self, n: usize) -> bool {
+        self.n_slot(n)
+            .is_some_and(|i| self.used.lock().unwrap().ns[i] >= self.limits.n_quotas[i].1)
+    }
+
+    /// Reserve room for an output of `bytes` bytes.
+    pub fn reserve(&self, mode: Mode, n: usize, bytes: u64) -> Result<Reservation<'_>, Refusal> {
+        let mut used = self.used.lock().unwrap();
+        if self.limits.max_files.is_some_and(|max| used.files >= max) {
+            return Err(Refusal::Exhausted(Reason::Budget("file")));
+        }
+        if self
+            .limits
+            .max_bytes
+            .is_some_and(|max| used.bytes + bytes > max)
+        {
+            return Err(Refusal::Exhausted(Reason::Budget("size")));
+        }
+        let mode = self.mode_slot(mode);
+        let n = self.n_slot(n);
+        if mode.is_some_and(|i| used.modes[i] >= self.limits.mode_quotas[i].1)
+            || n.is_some_and(|i| used.ns[i] >= self.limits.n_quotas[i].1)
+        {
+            return Err(Refusal::Quota);
+        }
+        used.files += 1;
+        used.bytes += bytes;
+        if let Some(i) = mode {
+            used.modes[i] += 1;
+        }
+        if let Some(i) = n {
+            used.ns[i] += 1;
+        }
+        Ok(Reservation {
+            budgets: self,
+            mode,
+            n,
+            bytes,
+            committed: false,
+        })
+    }
+
+    /// Usage of every configured budget, e.g. `files 10/10, std 4/5`.
+    pub fn report(&self) -> String {
+        let used = self.used.lock().unwrap();
+        let mut out = String::new();
+        let mut item = |s: String| {
+            if !out.is_empty() {
+                out += ", ";
+            }
+            out += &s;
+        };
+        const MIB: f64 = 1024.0 * 1024.0;
+        if let Some(max) = self.limits.max_bytes {
+            item(format!(
+                "size {:.1}/{:.1} MiB",
+                used.bytes as f64 / MIB,
+                max as f64 / MIB
+            ));
+        }
+        if let Some(max) = self.limits.max_files {
+            item(format!("files {}/{max}", used.files));
+        }
+        if let Some(max) = self.limits.max_time {
+            item(format!("time limit {max:?}"));
+        }
+        for (&(mode, max), used) in self.limits.mode_quotas.iter().zip(&used.modes) {
+            item(format!("{mode} {used}/{max}"));
+        }
+        for (&(n, max), used) in self.limits.n_quotas.iter().zip(&used.ns) {
+            item(format!("n={n} {used}/{max}"));
+        }
+        out
+    }
+}
+
+impl Reservation<'_> {
+    /// Keep the reserved budget: the output was written.
+    pub fn commit(mut self) {
+        self.budgets.used.lock().unwrap().written += 1;
+        self.committed = true;
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        let mut used = self.budgets.used.lock().unwrap();
+        used.files -= 1;
+        used.bytes -= self.bytes;
+        if let Some(i) = self.mode {
+            used.modes[i] -= 1;
+        }
+        if let Some(i) = self.n {
+            used.ns[i] -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budgets() -> Budgets {
+        Budgets::new(Limits {
+            max_files: Some(3),
+            max_bytes: Some(100),
+            mode_quotas: vec![(Mode::Standard, 2)],
+            n_quotas: vec![(2, 1)],
+            ..Limits::default()
+        })
+    }
+
+    fn reserve(budgets: &Budgets, mode: Mode, n: usize, bytes: u64) -> Reservation<'_> {
+        budgets
+            .reserve(mode, n, bytes)
+            .unwrap_or_else(|_| panic!("{mode} n={n} {bytes} bytes refused"))
+    }
+
+    #[test]
+    fn dropped_reservation_frees_its_quota() {
+        let budgets = budgets();
+        let reservation = reserve(&budgets, Mode::Standard, 2, 10);
+        assert!(budgets.n_full(2));
+        assert!(!budgets.admits(Mode::Atan, 2));
+        drop(reservation);
+        assert!(!budgets.n_full(2));
+        assert!(budgets.admits(Mode::Atan, 2));
+        assert!(!budgets.files_full());
+        let used = budgets.used.lock().unwrap();
+        assert_eq!((used.files, used.bytes), (0, 0));
+        assert_eq!((used.modes[0], used.ns[0]), (0, 0));
+    }
+
+    #[test]
+    fn committed_reservation_keeps_its_quota() {
+        let budgets = budgets();
+        reserve(&budgets, Mode::Standard, 2, 10).commit();
+        assert!(budgets.n_full(2));
+        assert!(matches!(
+            budgets.reserve(Mode::Atan, 2, 10),
+            Err(Refusal::Quota)
+        ));
+        assert!(!budgets.n_full(3));
+        let used = budgets.used.lock().unwrap();
+        assert_eq!((used.files, used.bytes, used.written), (1, 10, 1));
+        assert_eq!((used.modes[0], used.ns[0]), (1, 1));
+    }
+
+    #[test]
+    fn budgets_fill_up() {
+        let budgets = budgets();
+        assert!(matches!(
+            budgets.reserve(Mode::Atan, 3, 101),
+            Err(Refusal::Exhausted(Reason::Budget("size")))
+        ));
+        for mode in [Mode::Standard, Mode::Standard] {
+            reserve(&budgets, mode, 3, 10).commit();
+        }
+        assert!(!budgets.admits(Mode::Standard, 3));
+        assert!(budgets.all_full(&[Mode::Standard], &[3]));
+        assert!(!budgets.all_full(&[Mode::Standard, Mode::Atan], &[3]));
+        let pending = reserve(&budgets, Mode::Atan, 3, 10);
+        assert!(!budgets.files_full());
+        assert!(matches!(
+            budgets.reserve(Mode::Atan, 3, 10),
+            Err(Refusal::Exhausted(Reason::Budget("file")))
+        ));
+        pending.commit();
+        assert!(budgets.files_full());
+    }
+}
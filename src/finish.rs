@@ -0,0 +1,124 @@
+//! Output finishing: sample-rate conversion and normalisation.
+//!
+//! Every merge ends here. Looping outputs are resampled cyclically so the loop
+//! stays seamless; the level is then scaled to a peak, RMS or loudness target,
+//! held under a true-peak ceiling when one is given.
+
+use fundsp::wave::Wave;
+
+use crate::{loops::Seam, loudness, resample};
+
+/// Level an output is normalised to. Parsed from `peak:-1` (sample peak in
+/// dBFS), `rms:-18` (dBFS) or `lufs:-16` (integrated loudness per BS.1770).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Target {
+    Peak(f32),
+    Rms(f32),
+    Lufs(f32),
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Peak(db) => write!(f, "peak:{db}"),
+            Target::Rms(db) => write!(f, "rms:{db}"),
+            Target::Lufs(lufs) => write!(f, "lufs:{lufs}"),
+        }
+    }
+}
+
+impl std::str::FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, level) = s
+            .split_once(':')
+            .ok_or(format!("{s}: expected peak:DB, rms:DB or lufs:LUFS"))?;
+        let target: fn(f32) -> Target = match kind {
+            "peak" => Target::Peak,
+            "rms" => Target::Rms,
+            "lufs" => Target::Lufs,
+            k => return Err(format!("{s}: unknown target `{k}` (peak, rms, lufs)")),
+        };
+        let level: f32 = level.trim().parse().map_err(|e| format!("{s}: {e}"))?;
+        if !(level.is_finite() && level <= 0.0) {
+            return Err(format!("{s}: the level must be at most 0"));
+        }
+        Ok(target(level))
+    }
+}
+
+/// Final stage of every merge: looping, sample-rate conversion, then
+/// normalisation. The default cycles the result with a hard wrap, keeps the
+/// first input's rate and peak-normalises to 0 dBFS.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Finish {
+    /// Render the result as whole cycles of a seamless loop.
+    pub seam: Option<Seam>,
+    /// Output sample rate in Hz; `None` keeps the inputs' rate.
+    pub sample_rate: Option<u32>,
+    pub target: Target,
+    /// True-peak ceiling in dBTP. Without one, RMS and loudness targets are
+    /// still held to a sample peak of 0 dBFS so nothing clips.
+    pub ceiling: Option<f32>,
+}
+
+impl Default for Finish {
+    fn default() -> Self {
+        Finish {
+            seam: None,
+            sample_rate: None,
+            target: Target::Peak(0.0),
+            ceiling: None,
+        }
+    }
+}
+
+impl Finish {
+    /// Convert `wave` (a whole number of loop periods when looping) to the
+    /// output rate and scale it to the target level, backing off as far as the
+    /// ceiling requires.
+    pub fn apply(self, mut wave: Wave) -> Wave {
+        let rate = self.sample_rate.map(f64::from);
+        if let Some(rate) = rate.filter(|&r| r != wave.sample_rate()) {
+            let ratio = rate / wave.sample_rate();
+            let convert = match self.seam {
+                Some(_) => resample::resample_cyclic,
+                None => resample::resample,
+            };
+            let mut converted = Wave::new(0, rate);
+            for ch in 0..wave.channels() {
+                converted.push_channel(&convert(wave.channel(ch), ratio));
+            }
+            wave = converted;
+        }
+
+        let peak = wave.amplitude();
+        if peak == 0.0 {
+            return wave;
+        }
+        let db = |db: f32| 10f32.powf(db / 20.0);
+        let mut gain = match self.target {
+            Target::Peak(level) => db(level) / peak,
+            Target::Rms(level) => db(level) / loudness::rms(&wave),
+            // Silence after gating has no loudness to match; leave it be.
+            Target::Lufs(level) => match loudness::integrated(&wave) {
+                Some(lufs) => db(level - lufs as f32),
+                None => 1.0,
+            },
+        };
+        match self.ceiling {
+            Some(ceiling) => gain = gain.min(db(ceiling) / loudness::true_peak(&wave)),
+            None if !matches!(self.target, Target::Peak(_)) => gain = gain.min(1.0 / peak),
+            None => {}
+        }
+        if gain != 1.0 {
+            for ch in 0..wave.channels() {
+                for i in 0..wave.len() {
+                    wave.set(ch, i, wave.at(ch, i) * gain);
+                }
+            }
+        }
+        wave
+    }
+}
@@ -0,0 +1,569 @@
+//! FLAC encoding.
+//!
+//! A small encoder covering what the generator needs: fixed-size blocks, the
+//! fixed polynomial predictors of order 0–4 (or verbatim/constant subframes when
+//! they are cheaper), partitioned Rice coding of the residual and, for stereo,
+//! the best of the four channel decorrelations. Metadata is a STREAMINFO block,
+//! with the MD5 of the audio, and a VORBIS_COMMENT block.
+
+use std::io;
+
+use md5::{Digest, Md5};
+
+/// Most channels a FLAC stream can hold.
+pub const MAX_CHANNELS: usize = 8;
+
+/// Samples per channel in each frame.
+const BLOCK_SIZE: usize = 4096;
+
+/// Highest predictor order tried.
+const MAX_ORDER: usize = 4;
+
+/// Highest residual partition order tried.
+const MAX_PARTITION_ORDER: u32 = 8;
+
+/// Appends big-endian bit fields to a byte buffer.
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        BitWriter {
+            bytes: Vec::new(),
+            acc: 0,
+            bits: 0,
+        }
+    }
+
+    /// Write the low `n` (≤ 32) bits of `v`.
+    fn put(&mut self, n: u32, v: u64) {
+        debug_assert!(n <= 32);
+        if n == 0 {
+            return;
+        }
+        self.acc = (self.acc << n) | (v & ((1 << n) - 1));
+        self.bits += n;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.bytes.push((self.acc >> self.bits) as u8);
+        }
+    }
+
+    /// Write `v` as an `n`-bit two's complement number.
+    fn put_signed(&mut self, n: u32, v: i64) {
+        self.put(n, v as u64);
+    }
+
+    /// Write `q` zeros followed by a one.
+    fn put_unary(&mut self, mut q: u64) {
+        while q >= 32 {
+            self.put(32, 0);
+            q -= 32;
+        }
+        self.put(q as u32 + 1, 1);
+    }
+
+    /// Pad with zeros to a byte boundary.
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.put(8 - self.bits, 0);
+        }
+    }
+}
+
+/// CRC-8 with polynomial x^8 + x^2 + x + 1, as used for frame headers.
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |mut crc, &b| {
+        crc ^= b;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+/// CRC-16 with polynomial x^16 + x^15 + x^2 + 1, as used for whole frames.
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |mut crc, &b| {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+/// Map a signed residual to an unsigned one (0, -1, 1, -2, … → 0, 1, 2, 3, …).
+fn zigzag(r: i64) -> u64 {
+    ((r << 1) ^ (r >> 63)) as u64
+}
+
+/// Residual of the fixed predictor of `order` for the samples after the warm-up.
+fn fixed_residual(x: &[i64], order: usize) -> Vec<i64> {
+    let mut r = x.to_vec();
+    for _ in 0..order {
+        for i in (1..r.len()).rev() {
+            r[i] -= r[i - 1];
+        }
+    }
+    r.split_off(order.min(r.len()))
+}
+
+/// Rice coding plan for a residual: the partition order and one parameter per
+/// partition, with its size in bits.
+struct RicePlan {
+    order: u32,
+    params: Vec<u32>,
+    bits: u64,
+}
+
+/// Best Rice parameter and its (estimated) cost in bits for `n` values summing
+/// to `sum`.
+fn rice_param(n: u64, sum: u64) -> (u32, u64) {
+    (0..=30)
+        .map(|k| (k, n * (k as u64 + 1) + (sum >> k)))
+        .min_by_key(|&(_, bits)| bits)
+        .unwrap()
+}
+
+/// Choose the partition order and parameters for the residual of a block of
+/// `block` samples whose first `warmup` samples are not coded. Parameters above
+/// 14 switch the whole residual to 5-bit parameter fields.
+fn plan_rice(residual: &[i64], block: usize, warmup: usize) -> RicePlan {
+    let u: Vec<u64> = residual.iter().map(|&r| zigzag(r)).collect();
+    let mut best: Option<RicePlan> = None;
+    for order in 0..=MAX_PARTITION_ORDER {
+        let parts = 1 << order;
+        if !block.is_multiple_of(parts) || block / parts <= warmup {
+            break;
+        }
+        let mut params = Vec::with_capacity(parts);
+        let mut bits = 0u64;
+        let mut pos = 0;
+        for p in 0..parts {
+            let len = block / parts - if p == 0 { warmup } else { 0 };
+            let sum: u64 = u[pos..pos + len].iter().sum();
+            let (k, b) = rice_param(len as u64, sum);
+            params.push(k);
+            bits += b + 4;
+            pos += len;
+        }
+        if params.iter().any(|&k| k > 14) {
+            bits += parts as u64;
+        }
+        if best.as_ref().is_none_or(|b| bits < b.bits) {
+            best = Some(RicePlan {
+                order,
+                params,
+                bits,
+            });
+        }
+    }
+    best.unwrap()
+}
+
+/// The cheapest way found to code one channel of a block.
+enum Subframe {
+    Constant(i64),
+    Verbatim(Vec<i64>),
+    Fixed {
+        order: usize,
+        warmup: Vec<i64>,
+        residual: Vec<i64>,
+        plan: RicePlan,
+    },
+}
+
+impl Subframe {
+    /// Pick a subframe for `x`, whose samples take `bps` bits.
+    fn choose(x: &[i64], bps: u32) -> (Subframe, u64) {
+        if x.iter().all(|&v| v == x[0]) {
+            return (Subframe::Constant(x[0]), 8 + bps as u64);
+        }
+        let mut best = (
+            Subframe::Verbatim(x.to_vec()),
+            8 + bps as u64 * x.len() as u64,
+        );
+        for order in 0..=MAX_ORDER.min(x.len() - 1) {
+            let residual = fixed_residual(x, order);
+            let plan = plan_rice(&residual, x.len(), order);
+            let bits = 8 + order as u64 * bps as u64 + 6 + plan.bits;
+            if bits < best.1 {
+                best = (
+                    Subframe::Fixed {
+                        order,
+                        warmup: x[..order].to_vec(),
+                        residual,
+                        plan,
+                    },
+                    bits,
+                );
+            }
+        }
+        best
+    }
+
+    fn write(&self, w: &mut BitWriter, bps: u32) {
+        match self {
+            Subframe::Constant(v) => {
+                w.put(8, 0b0000_0000);
+                w.put_signed(bps, *v);
+            }
+            Subframe::Verbatim(x) => {
+                w.put(8, 0b0000_0010);
+                for &v in x {
+                    w.put_signed(bps, v);
+                }
+            }
+            Subframe::Fixed {
+                order,
+                warmup,
+                residual,
+                plan,
+            } => {
+                w.put(8, (0b00_1000 | *order as u64) << 1);
+                for &v in warmup {
+                    w.put_signed(bps, v);
+                }
+                let wide = plan.params.iter().any(|&k| k > 14);
+                let param_bits = if wide { 5 } else { 4 };
+                w.put(2, wide as u64);
+                w.put(4, plan.order as u64);
+                let parts = 1 << plan.order;
+                let block = residual.len() + order;
+                let mut pos = 0;
+                for (p, &k) in plan.params.iter().enumerate() {
+                    w.put(param_bits, k as u64);
+                    let len = block / parts - if p == 0 { *order } else { 0 };
+                    for &r in &residual[pos..pos + len] {
+                        let u = zigzag(r);
+                        w.put_unary(u >> k);
+                        w.put(k, u);
+                    }
+                    pos += len;
+                }
+            }
+        }
+    }
+}
+
+/// Append `v` in FLAC's extended UTF-8 coding.
+fn put_utf8(w: &mut BitWriter, v: u64) {
+    if v < 0x80 {
+        w.put(8, v);
+        return;
+    }
+    let mut extra = 1;
+    while v >> (6 * extra) >= 1 << (6 - extra) {
+        extra += 1;
+    }
+    let lead = (0xff00u64 >> (extra + 1)) & 0xff;
+    w.put(8, lead | (v >> (6 * extra)));
+    for i in (0..extra).rev() {
+        w.put(8, 0x80 | ((v >> (6 * i)) & 0x3f));
+    }
+}
+
+/// Encode one frame of `block` samples per channel.
+fn frame(out: &mut Vec<u8>, number: u64, channels: &[&[i64]], sample_size: u32) {
+    let block = channels[0].len();
+    // Independent channels, or for stereo the cheapest of left/side, side/right
+    // and mid/side.
+    let (assignment, subframes): (u64, Vec<(Subframe, u32)>) = if channels.len() == 2 {
+        let (l, r) = (channels[0], channels[1]);
+        let side: Vec<i64> = l.iter().zip(r).map(|(a, b)| a - b).collect();
+        let mid: Vec<i64> = l.iter().zip(r).map(|(a, b)| (a + b) >> 1).collect();
+        let (left, lb) = Subframe::choose(l, sample_size);
+        let (right, rb) = Subframe::choose(r, sample_size);
+        let (side, sb) = Subframe::choose(&side, sample_size + 1);
+        let (mid, mb) = Subframe::choose(&mid, sample_size);
+        let costs = [lb + rb, lb + sb, sb + rb, mb + sb];
+        let best = (0..4).min_by_key(|&i| costs[i]).unwrap();
+        let (ls, ss) = (sample_size, sample_size + 1);
+        match best {
+            0 => (1, vec![(left, ls), (right, ls)]),
+            1 => (0b1000, vec![(left, ls), (side, ss)]),
+            2 => (0b1001, vec![(side, ss), (right, ls)]),
+            _ => (0b1010, vec![(mid, ls), (side, ss)]),
+        }
+    } else {
+        let subframes = channels
+            .iter()
+            .map(|x| (Subframe::choose(x, sample_size).0, sample_size))
+            .collect();
+        (channels.len() as u64 - 1, subframes)
+    };
+
+    let mut w = BitWriter::new();
+    w.put(16, 0xfff8);
+    // Block size: 4096 has its own code, others follow the header as 16 bits.
+    w.put(4, if block == BLOCK_SIZE { 0b1100 } else { 0b0111 });
+    // Sample rate from STREAMINFO.
+    w.put(4, 0);
+    w.put(4, assignment);
+    w.put(3, if sample_size == 16 { 0b100 } else { 0b110 });
+    w.put(1, 0);
+    put_utf8(&mut w, number);
+    if block != BLOCK_SIZE {
+        w.put(16, block as u64 - 1);
+    }
+    let crc = crc8(&w.bytes);
+    w.put(8, crc as u64);
+    for (subframe, bps) in &subframes {
+        subframe.write(&mut w, *bps);
+    }
+    w.align();
+    let crc = crc16(&w.bytes);
+    w.put(16, crc as u64);
+    out.extend_from_slice(&w.bytes);
+}
+
+/// Encode interleaved integer `samples` of `bits_per_sample` (16 or 24) bits as
+/// a FLAC file with the given Vorbis comments. Fails for more than
+/// [`MAX_CHANNELS`] channels.
+pub fn encode(
+    samples: &[i32],
+    channels: usize,
+    sample_rate: u32,
+    bits_per_sample: u32,
+    comments: &[(&str, String)],
+) -> io::Result<Vec<u8>> {
+    if !(1..=MAX_CHANNELS).contains(&channels) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("FLAC holds 1 to {MAX_CHANNELS} channels, not {channels}"),
+        ));
+    }
+    let frames = samples.len() / channels;
+    let mut md5 = Md5::new();
+    for &s in samples {
+        md5.update(&s.to_le_bytes()[..bits_per_sample as usize / 8]);
+    }
+
+    let mut audio = Vec::new();
+    let (mut min_frame, mut max_frame) = (u32::MAX, 0);
+    let deinterleaved: Vec<Vec<i64>> = (0..channels)
+        .map(|c| {
+            samples
+                .iter()
+                .skip(c)
+                .step_by(channels)
+                .map(|&s| s as i64)
+                .collect()
+        })
+        .collect();
+    for (number, start) in (0..frames).step_by(BLOCK_SIZE).enumerate() {
+        let end = (start + BLOCK_SIZE).min(frames);
+        let block: Vec<&[i64]> = deinterleaved.iter().map(|c| &c[start..end]).collect();
+        let before = audio.len();
+        frame(&mut audio, number as u64, &block, bits_per_sample);
+        let size = (audio.len() - before) as u32;
+        min_frame = min_frame.min(size);
+        max_frame = max_frame.max(size);
+    }
+    if frames == 0 {
+        min_frame = 0;
+    }
+
+    let mut out = b"fLaC".to_vec();
+    // STREAMINFO.
+    let mut w = BitWriter::new();
+    w.put(1, 0);
+    w.put(7, 0);
+    w.put(24, 34);
+    // Fixed block size; only the last block may be shorter.
+    w.put(16, BLOCK_SIZE as u64);
+    w.put(16, BLOCK_SIZE as u64);
+    w.put(24, min_frame as u64);
+    w.put(24, max_frame as u64);
+    w.put(20, sample_rate as u64);
+    w.put(3, channels as u64 - 1);
+    w.put(5, bits_per_sample as u64 - 1);
+    w.put(4, (frames as u64) >> 32);
+    w.put(32, frames as u64 & 0xffff_ffff);
+    out.extend_from_slice(&w.bytes);
+    out.extend_from_slice(&md5.finalize());
+
+    // VORBIS_COMMENT, the last metadata block; its fields are little-endian.
+    let vendor = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+    let mut block = Vec::new();
+    block.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    block.extend_from_slice(vendor.as_bytes());
+    block.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for (key, value) in comments {
+        let field = format!("{key}={value}");
+        block.extend_from_slice(&(field.len() as u32).to_le_bytes());
+        block.extend_from_slice(field.as_bytes());
+    }
+    out.push(0x80 | 4);
+    out.extend_from_slice(&(block.len() as u32).to_be_bytes()[1..]);
+    out.extend_from_slice(&block);
+
+    out.extend_from_slice(&audio);
+    Ok(out)
+}
+
+/// Read the Vorbis comments back from a FLAC file, or `None` if it has none.
+pub fn read_comments(bytes: &[u8]) -> Option<Vec<(String, String)>> {
+    if bytes.get(..4)? != b"fLaC" {
+        return None;
+    }
+    let u32_le = |b: &[u8], at: usize| -> Option<usize> {
+        Some(u32::from_le_bytes(b.get(at..at + 4)?.try_into().ok()?) as usize)
+    };
+    let mut pos = 4;
+    loop {
+        let &[header, a, b, c] = bytes.get(pos..pos + 4)? else {
+            return None;
+        };
+        let len = u32::from_be_bytes([0, a, b, c]) as usize;
+        let block = bytes.get(pos + 4..pos + 4 + len)?;
+        if header & 0x7f == 4 {
+            let mut at = 4 + u32_le(block, 0)?;
+            let count = u32_le(block, at)?;
+            at += 4;
+            let mut comments = Vec::with_capacity(count);
+            for _ in 0..count {
+                let len = u32_le(block, at)?;
+                let field = std::str::from_utf8(block.get(at + 4..at + 4 + len)?).ok()?;
+                let (key, value) = field.split_once('=')?;
+                comments.push((key.to_owned(), value.to_owned()));
+                at += 4 + len;
+            }
+            return Some(comments);
+        }
+        if header & 0x80 != 0 {
+            return None;
+        }
+        pos += 4 + len;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use symphonia::core::audio::SampleBuffer;
+    use symphonia::core::codecs::DecoderOptions;
+    use symphonia::core::errors::Error;
+    use symphonia::core::io::MediaSourceStream;
+
+    /// Decode `bytes` with an independent decoder, checking the STREAMINFO MD5,
+    /// and return the interleaved samples at their original bit depth.
+    fn decode(bytes: Vec<u8>, bits_per_sample: u32) -> Vec<i32> {
+        let source = MediaSourceStream::new(Box::new(io::Cursor::new(bytes)), Default::default());
+        let mut format = symphonia::default::get_probe()
+            .format(
+                &Default::default(),
+                source,
+                &Default::default(),
+                &Default::default(),
+            )
+            .unwrap()
+            .format;
+        let track = format.default_track().unwrap();
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions { verify: true })
+            .unwrap();
+        let mut samples = Vec::new();
+        loop {
+            let packet = match format.next_packet() {
+                Ok(packet) => packet,
+                Err(Error::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => panic!("{e}"),
+            };
+            let decoded = decoder.decode(&packet).unwrap();
+            let mut buffer = SampleBuffer::<i32>::new(decoded.capacity() as u64, *decoded.spec());
+            buffer.copy_interleaved_ref(decoded);
+            samples.extend(buffer.samples().iter().map(|s| s >> (32 - bits_per_sample)));
+        }
+        assert_eq!(decoder.finalize().verify_ok, Some(true));
+        samples
+    }
+
+    /// Deterministic test signal mixing a tone, noise, silence and full-scale
+    /// extremes, with channels that are partly correlated.
+    fn signal(frames: usize, channels: usize, bits_per_sample: u32) -> Vec<i32> {
+        let max = (1i64 << (bits_per_sample - 1)) - 1;
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut noise = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as i64
+        };
+        let mut samples = Vec::with_capacity(frames * channels);
+        for i in 0..frames {
+            let tone = ((i as f64 * 0.05).sin() * max as f64 * 0.5) as i64;
+            for c in 0..channels {
+                let v = match (i / 1000) % 4 {
+                    0 => tone + (c as i64 * 37),
+                    1 => noise() % (max + 1),
+                    2 => 0,
+                    _ => [max, -max - 1][(i + c) % 2],
+                };
+                samples.push(v.clamp(-max - 1, max) as i32);
+            }
+        }
+        samples
+    }
+
+    #[test]
+    fn crcs_match_check_values() {
+        // Catalogued check values: CRC-8/SMBUS and CRC-16/UMTS.
+        assert_eq!(crc8(b"123456789"), 0xf4);
+        assert_eq!(crc16(b"123456789"), 0xfee8);
+        assert_eq!(crc8(b""), 0);
+        assert_eq!(crc16(b""), 0);
+    }
+
+    #[test]
+    fn encoding_round_trips() {
+        for bits_per_sample in [16, 24] {
+            for channels in [1, 2] {
+                // Cover a short final block and a stream shorter than the
+                // predictor warm-up.
+                for frames in [2 * BLOCK_SIZE + 1234, 3] {
+                    let samples = signal(frames, channels, bits_per_sample);
+                    let bytes = encode(&samples, channels, 44100, bits_per_sample, &[]).unwrap();
+                    assert_eq!(
+                        decode(bytes, bits_per_sample),
+                        samples,
+                        "{bits_per_sample}-bit, {channels} channel(s), {frames} frames"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn comments_round_trip() {
+        let comments = [
+            ("TITLE", "a = b".to_owned()),
+            ("SOURCE", "/tmp/ünïcødé/x.wav".to_owned()),
+            ("EMPTY", String::new()),
+        ];
+        let bytes = encode(&signal(10, 2, 16), 2, 8000, 16, &comments).unwrap();
+        let read = read_comments(&bytes).unwrap();
+        let read: Vec<_> = read.iter().map(|(k, v)| (k.as_str(), v.clone())).collect();
+        assert_eq!(read, comments);
+        let bytes = encode(&signal(10, 1, 16), 1, 8000, 16, &[]).unwrap();
+        assert_eq!(read_comments(&bytes), Some(vec![]));
+    }
+
+    #[test]
+    fn too_many_channels_are_refused() {
+        assert!(encode(&[0; 9], MAX_CHANNELS + 1, 8000, 16, &[]).is_err());
+        assert!(encode(&[], 0, 8000, 16, &[]).is_err());
+    }
+}
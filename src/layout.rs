@@ -0,0 +1,339 @@
+//! Output name templates.
+//!
+//! A template such as `{mode}/{src0_stem}/{hash:8}-{hash}` names every output
+//! relative to the output directory; the codec's extension is appended. It is
+//! filled in two steps: everything known from the merge parameters first, so
+//! the existence check can usually run before rendering, and the properties of
+//! the rendered output (`{channels}`, `{duration_bucket}`) last.
+
+use fundsp::wave::Wave;
+
+use crate::MergeParams;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Text(String),
+    /// The hash, or its first N digits.
+    Hash(Option<usize>),
+    Mode,
+    /// Number of inputs.
+    N,
+    /// File stem of input K.
+    SrcStem(usize),
+    Channels,
+    DurationBucket,
+}
+
+/// An output name with placeholders.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+/// Upper bounds in seconds and names of the `{duration_bucket}` values.
+const BUCKETS: [(f64, &str); 6] = [
+    (1.0, "under-1s"),
+    (2.0, "1-2s"),
+    (5.0, "2-5s"),
+    (10.0, "5-10s"),
+    (30.0, "10-30s"),
+    (60.0, "30-60s"),
+];
+
+/// Make a substituted value safe as (part of) one path component.
+fn component(value: &str) -> String {
+    match value {
+        "" | "." | ".." => "_".to_owned(),
+        _ => value.replace(['/', '\\', ':'], "_"),
+    }
+}
+
+impl Template {
+    /// Fill in the placeholders known before rendering.
+    pub fn inputs(&self, hash: &str, params: &MergeParams) -> Template {
+        self.fill(|part| match *part {
+            Part::Hash(digits) => Some(hash[..digits.unwrap_or(hash.len())].to_owned()),
+            Part::Mode => Some(component(&params.mode.to_string())),
+            Part::N => Some(params.inputs.len().to_string()),
+            Part::SrcStem(k) => Some(match params.inputs.get(k) {
+                Some(inp) => component(&inp.path.file_stem().unwrap_or_default().to_string_lossy()),
+                None => "-".to_owned(),
+            }),
+            _ => None,
+        })
+    }
+
+    /// Fill in the placeholders that describe the rendered `wave`.
+    pub fn output(&self, wave: &Wave) -> Template {
+        self.fill(|part| match part {
+            Part::Channels => Some(wave.channels().to_string()),
+            Part::DurationBucket => Some(
+                BUCKETS
+                    .iter()
+                    .find(|&&(max, _)| wave.duration() < max)
+                    .map_or("over-60s", |&(_, name)| name)
+                    .to_owned(),
+            ),
+            _ => None,
+        })
+    }
+
+    fn fill(&self, value: impl Fn(&Part) -> Option<String>) -> Template {
+        let parts = self
+            .parts
+            .iter()
+            .map(|part| value(part).map_or_else(|| part.clone(), Part::Text))
+            .collect();
+        Template { parts }
+    }
+
+    /// Whether the name depends on the rendered output.
+    pub fn needs_output(&self) -> bool {
+        self.parts
+            .iter()
+            .any(|part| matches!(part, Part::Channels | Part::DurationBucket))
+    }
+
+    /// The name, once every placeholder is filled in.
+    pub fn name(&self) -> Option<String> {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Where the full hash sits within its path component of a name: a fixed
+/// number of bytes after the component's start or before its end.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Anchor {
+    Start(usize),
+    End(usize),
+}
+
+impl Part {
+    /// Length in bytes of the part's value, if it is always the same.
+    fn width(&self) -> Option<usize> {
+        match self {
+            Part::Text(text) => Some(text.len()),
+            Part::Hash(digits) => Some(digits.unwrap_or(64)),
+            _ => None,
+        }
+    }
+}
+
+/// Bytes from the hash to the nearest `/`, or the end of the name, across
+/// `parts` running away from it; `None` if a part on the way varies in length.
+fn span<'a>(parts: impl Iterator<Item = &'a Part>, backwards: bool) -> Option<usize> {
+    let mut width = 0;
+    for part in parts {
+        if let Part::Text(text) = part {
+            let rest = match backwards {
+                true => text.rfind('/').map(|i| text.len() - i - 1),
+                false => text.find('/'),
+            };
+            if let Some(rest) = rest {
+                return Some(width + rest);
+            }
+        }
+        width += part.width()?;
+    }
+    Some(width)
+}
+
+impl Template {
+    /// The path component holding the first `{hash}` and its anchor, if the
+    /// rest of that component is fixed-width on at least one side.
+    fn hash_anchor(&self) -> Option<(usize, Anchor)> {
+        let at = self
+            .parts
+            .iter()
+            .position(|part| *part == Part::Hash(None))?;
+        let (before, after) = (&self.parts[..at], &self.parts[at + 1..]);
+        let component = before
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.matches('/').count(),
+                _ => 0,
+            })
+            .sum();
+        let anchor = span(before.iter().rev(), true)
+            .map(Anchor::Start)
+            .or_else(|| span(after.iter(), false).map(Anchor::End))?;
+        Some((component, anchor))
+    }
+
+    /// The full hash in `name`, an output name made from this template with
+    /// `extension` appended. Names with another extension hold none, just as
+    /// the existence check of other layouts only finds names with the codec's.
+    pub fn hash_in<'a>(&self, name: &'a str, extension: &str) -> Option<&'a str> {
+        let (component, anchor) = self.hash_anchor()?;
+        let stem = name.strip_suffix(extension)?.strip_suffix('.')?;
+        let component = stem.split('/').nth(component)?;
+        let start = match anchor {
+            Anchor::Start(offset) => offset,
+            Anchor::End(offset) => component.len().checked_sub(offset + 64)?,
+        };
+        let hash = component.get(start..start + 64)?;
+        hash.bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+            .then_some(hash)
+    }
+}
+
+impl std::str::FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(open) = rest.find('{') {
+            if open > 0 {
+                parts.push(Part::Text(rest[..open].to_owned()));
+            }
+            let close = rest[open..]
+                .find('}')
+                .ok_or(format!("{s}: unclosed `{{`"))?;
+            let name = &rest[open + 1..open + close];
+            let src = name
+                .strip_prefix("src")
+                .and_then(|k| k.strip_suffix("_stem"))
+                .and_then(|k| k.parse().ok());
+            parts.push(match (name, src) {
+                (_, Some(k)) => Part::SrcStem(k),
+                ("hash", _) => Part::Hash(None),
+                ("mode", _) => Part::Mode,
+                ("n", _) => Part::N,
+                ("channels", _) => Part::Channels,
+                ("duration_bucket", _) => Part::DurationBucket,
+                _ => match name.strip_prefix("hash:").map(str::parse) {
+                    Some(Ok(digits @ 1..=64)) => Part::Hash(Some(digits)),
+                    _ => {
+                        return Err(format!(
+                            "{s}: unknown placeholder `{{{name}}}` (hash, hash:N, mode, n, \
+                             srcK_stem, channels, duration_bucket)"
+                        ));
+                    }
+                },
+            });
+            rest = &rest[open + close + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_owned()));
+        }
+        // Deduplication finds outputs by their full hash.
+        if !parts.contains(&Part::Hash(None)) {
+            return Err(format!("{s}: the template must contain `{{hash}}`"));
+        }
+        if s.starts_with('/') || s.split('/').any(|c| c == "..") {
+            return Err(format!("{s}: names must stay inside the output directory"));
+        }
+        let template = Template { parts };
+        // ...and must be able to tell where it starts in an existing name.
+        if template.hash_anchor().is_none() {
+            return Err(format!(
+                "{s}: within its directory, `{{hash}}` needs only text and `{{hash:N}}` on \
+                 one side"
+            ));
+        }
+        Ok(template)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    fn template(s: &str) -> Template {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn bad_templates_are_rejected() {
+        for (s, error) in [
+            ("{mode}/{hash:8}", "must contain `{hash}`"),
+            ("{hash", "unclosed `{`"),
+            ("{hash}/{stem}", "unknown placeholder `{stem}`"),
+            ("{hash:0}/{hash}", "unknown placeholder `{hash:0}`"),
+            ("{hash:65}/{hash}", "unknown placeholder `{hash:65}`"),
+            ("/{hash}", "must stay inside"),
+            ("{mode}/../{hash}", "must stay inside"),
+            ("{mode}{hash}{n}", "needs only text"),
+            ("x/{src0_stem}-{hash}-{channels}/y", "needs only text"),
+            ("{src12_stem}-{hash}_{channels}/x", "needs only text"),
+        ] {
+            let e = s.parse::<Template>().unwrap_err();
+            assert!(e.contains(error), "{s}: {e}");
+        }
+        for s in [
+            "{hash}",
+            "{hash:4}/{hash}",
+            "{mode}/{n}/{src0_stem}/{hash:8}-{hash}",
+            "{src12_stem}-{hash}_{hash:2}/{channels}",
+            "{duration_bucket}/{hash:2}{hash}",
+        ] {
+            assert!(s.parse::<Template>().is_ok(), "{s}");
+        }
+    }
+
+    #[test]
+    fn names_depending_on_the_output_are_recognised() {
+        assert!(!template("{mode}/{n}/{src0_stem}/{hash:2}/{hash}").needs_output());
+        assert!(template("{channels}/{hash}").needs_output());
+        assert!(template("{hash}-{duration_bucket}").needs_output());
+    }
+
+    #[test]
+    fn hashes_are_found_through_the_template() {
+        let hash = |t: &str, name: &str| template(t).hash_in(name, "wav").map(str::to_owned);
+        let full = Some(HASH.to_owned());
+        assert_eq!(hash("{hash}", &format!("{HASH}.wav")), full);
+        assert_eq!(hash("{hash:4}/{hash}", &format!("0123/{HASH}.wav")), full);
+        // Leading digits that are themselves hex must not shift the hash.
+        assert_eq!(hash("{hash:2}{hash}", &format!("01{HASH}.wav")), full);
+        assert_eq!(
+            hash(
+                "{mode}/{hash:8}-{hash}",
+                &format!("std/01234567-{HASH}.wav")
+            ),
+            full
+        );
+        // A hex stem before the hash is skipped by anchoring on the end.
+        let stem = "a".repeat(70);
+        assert_eq!(
+            hash(
+                "{channels}/{src0_stem}-{hash}",
+                &format!("2/{stem}-{HASH}.wav")
+            ),
+            full
+        );
+        assert_eq!(
+            hash("{src0_stem}-{hash}.x", &format!("{stem}-{HASH}.x.wav")),
+            full
+        );
+        assert_eq!(
+            hash(
+                "{src0_stem}/{hash}_{channels}",
+                &format!("{stem}/{HASH}_2.wav")
+            ),
+            full
+        );
+        // Names that don't fit the template.
+        assert_eq!(hash("{hash}", HASH), None);
+        assert_eq!(hash("{hash:4}/{hash}", &format!("{HASH}.wav")), None);
+        assert_eq!(hash("{hash}", &format!("{}.wav", &HASH[1..])), None);
+        assert_eq!(
+            hash("{hash}", &format!("{}.wav", HASH.to_uppercase())),
+            None
+        );
+        assert_eq!(hash("{hash}", "notes.txt"), None);
+        // Outputs of another codec don't count as present.
+        assert_eq!(hash("{hash}", &format!("{HASH}.flac")), None);
+        assert_eq!(hash("{hash}", &format!("{HASH}wav")), None);
+    }
+}
@@ -0,0 +1,123 @@
+//! Seamless loops.
+//!
+//! A looping output is cut from the combined sequence at a seam, either
+//! between matching rising zero crossings or with a crossfade, and then
+//! stretched and repeated in whole periods so it wraps without a click.
+
+use crate::{Crossfade, Resampling, positive_f32, resample, rescale};
+
+/// How a looping output hides the seam where it wraps around. Parsed from
+/// `zero-crossing` or `crossfade:MS`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Seam {
+    /// Cut the loop between rising zero crossings with matching slopes.
+    ZeroCrossing,
+    /// Crossfade the end of the loop into its start over this many ms.
+    Crossfade(f32),
+}
+
+impl std::fmt::Display for Seam {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Seam::ZeroCrossing => f.write_str("zero-crossing"),
+            Seam::Crossfade(ms) => write!(f, "crossfade:{ms}"),
+        }
+    }
+}
+
+impl std::str::FromStr for Seam {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "zero-crossing" => Ok(Seam::ZeroCrossing),
+            Some(("crossfade", ms)) => Ok(Seam::Crossfade(positive_f32(ms)?)),
+            _ => Err(format!("{s}: expected zero-crossing or crossfade:MS")),
+        }
+    }
+}
+
+/// Where a loop is cut from a combined sequence: `start..end`, with the first
+/// `fade` samples crossfaded from the ones following `end`. Found on the first
+/// channel and reused for the others, so all channels loop together.
+#[derive(Clone, Copy)]
+pub struct LoopCut {
+    start: usize,
+    end: usize,
+    fade: usize,
+}
+
+impl LoopCut {
+    pub fn find(seq: &[f32], seam: Seam, sample_rate: f64) -> LoopCut {
+        let len = seq.len();
+        let whole = LoopCut {
+            start: 0,
+            end: len,
+            fade: 0,
+        };
+        match seam {
+            Seam::Crossfade(ms) => {
+                let fade = ((ms as f64 / 1000.0 * sample_rate).round() as usize).min(len / 2);
+                LoopCut {
+                    end: len - fade,
+                    fade,
+                    ..whole
+                }
+            }
+            Seam::ZeroCrossing => {
+                // Rising zero crossings and the slope there. The loop starts at
+                // the first and ends at the one in the last quarter whose slope
+                // matches best, so the wrap continues the waveform. Without a
+                // pair of crossings (e.g. DC) a short crossfade hides the seam.
+                let fallback = || LoopCut::find(seq, Seam::Crossfade(10.0), sample_rate);
+                let rising: Vec<(usize, f32)> = (1..len)
+                    .filter(|&i| seq[i - 1] < 0.0 && seq[i] >= 0.0)
+                    .map(|i| (i, seq[i] - seq[i - 1]))
+                    .collect();
+                let Some((&(start, slope), later)) = rising.split_first() else {
+                    return fallback();
+                };
+                let tail = later.partition_point(|&(i, _)| i < len - len / 4);
+                match later[tail..]
+                    .iter()
+                    .min_by(|a, b| (a.1 - slope).abs().total_cmp(&(b.1 - slope).abs()))
+                {
+                    Some(&(end, _)) => LoopCut {
+                        start,
+                        end,
+                        fade: 0,
+                    },
+                    None => fallback(),
+                }
+            }
+        }
+    }
+
+    /// One period of the loop cut from `seq`. The crossfade gains sum to one,
+    /// since the head and what follows the end are usually alike.
+    pub fn apply(self, seq: &[f32]) -> Vec<f32> {
+        let end = self.end.min(seq.len());
+        let mut body = seq[self.start.min(end)..end].to_vec();
+        for (i, (b, &next)) in body.iter_mut().zip(&seq[end..]).take(self.fade).enumerate() {
+            let (g_next, g_body) = Crossfade::SCurve.gains(i as f32 / self.fade as f32);
+            *b = g_next * next + g_body * *b;
+        }
+        body
+    }
+}
+
+/// Stretch one loop period by `rx / rs` and repeat it in whole periods, as many
+/// as fit in `take_len` samples but at least one.
+pub fn loop_stage(
+    body: Vec<f32>,
+    rx: usize,
+    rs: usize,
+    take_len: usize,
+    resampling: Resampling,
+) -> Vec<f32> {
+    let body = match resampling {
+        Resampling::Sinc if rx != rs => resample::resample_cyclic(&body, rx as f64 / rs as f64),
+        _ => rescale(body, rx, rs, resampling),
+    };
+    body.repeat((take_len / body.len().max(1)).max(1))
+}
@@ -0,0 +1,111 @@
+//! Level measurements for output normalisation.
+//!
+//! Integrated loudness follows ITU-R BS.1770-4: channels are K-weighted, mean
+//! squares are taken over 400 ms blocks overlapping by 75 %, and blocks below
+//! the absolute (-70 LUFS) and relative (-10 LU) gates are ignored. True peak is
+//! measured on a 4× oversampled signal.
+
+use fundsp::wave::Wave;
+
+use crate::resample::resample;
+
+/// A second-order IIR section, run in transposed direct form II.
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+}
+
+impl Biquad {
+    fn run(&self, input: &[f64]) -> Vec<f64> {
+        let (mut z1, mut z2) = (0.0, 0.0);
+        input
+            .iter()
+            .map(|&x| {
+                let y = self.b[0] * x + z1;
+                z1 = self.b[1] * x - self.a[0] * y + z2;
+                z2 = self.b[2] * x - self.a[1] * y;
+                y
+            })
+            .collect()
+    }
+}
+
+/// The two K-weighting stages (high shelf, then high pass), designed for any
+/// sample rate from the analogue prototypes behind the 48 kHz coefficients.
+fn k_weighting(sample_rate: f64) -> [Biquad; 2] {
+    let k = |f0: f64| (std::f64::consts::PI * f0 / sample_rate).tan();
+
+    let (k1, q1) = (k(1681.974450955533), 0.7071752369554196);
+    let vh = 10f64.powf(3.999843853973347 / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k1 / q1 + k1 * k1;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k1 / q1 + k1 * k1) / a0,
+            2.0 * (k1 * k1 - vh) / a0,
+            (vh - vb * k1 / q1 + k1 * k1) / a0,
+        ],
+        a: [2.0 * (k1 * k1 - 1.0) / a0, (1.0 - k1 / q1 + k1 * k1) / a0],
+    };
+
+    let (k2, q2) = (k(38.13547087602444), 0.5003270373238773);
+    let a0 = 1.0 + k2 / q2 + k2 * k2;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k2 * k2 - 1.0) / a0, (1.0 - k2 / q2 + k2 * k2) / a0],
+    };
+    [shelf, high_pass]
+}
+
+/// Integrated loudness in LUFS, or `None` if everything is gated away (silence).
+/// Signals shorter than one block are measured as a single block.
+pub fn integrated(wave: &Wave) -> Option<f64> {
+    let rate = wave.sample_rate();
+    let [shelf, high_pass] = k_weighting(rate);
+    // Squared K-weighted samples summed over channels (all weighted 1.0, as for
+    // the front channels of BS.1770).
+    let mut power = vec![0.0; wave.len()];
+    for ch in 0..wave.channels() {
+        let x: Vec<f64> = wave.channel(ch).iter().map(|&v| v as f64).collect();
+        for (p, y) in power.iter_mut().zip(high_pass.run(&shelf.run(&x))) {
+            *p += y * y;
+        }
+    }
+
+    let block = ((0.4 * rate).round() as usize).clamp(1, power.len().max(1));
+    let step = ((0.1 * rate).round() as usize).max(1);
+    let blocks: Vec<f64> = (0..=power.len().saturating_sub(block))
+        .step_by(step)
+        .map(|start| power[start..start + block].iter().sum::<f64>() / block as f64)
+        .collect();
+
+    let lufs = |z: f64| -0.691 + 10.0 * z.log10();
+    let gated_mean = |threshold: f64| {
+        let kept: Vec<f64> = blocks
+            .iter()
+            .copied()
+            .filter(|&z| z > 0.0 && lufs(z) > threshold)
+            .collect();
+        (!kept.is_empty()).then(|| kept.iter().sum::<f64>() / kept.len() as f64)
+    };
+    let relative = lufs(gated_mean(-70.0)?) - 10.0;
+    gated_mean(relative.max(-70.0)).map(lufs)
+}
+
+/// Root-mean-square level over all samples of all channels.
+pub fn rms(wave: &Wave) -> f32 {
+    let count = wave.channels() * wave.len();
+    let sum: f64 = (0..wave.channels())
+        .flat_map(|ch| wave.channel(ch).iter())
+        .map(|&v| v as f64 * v as f64)
+        .sum();
+    (sum / count.max(1) as f64).sqrt() as f32
+}
+
+/// Largest absolute value of the signal reconstructed at 4× the sample rate,
+/// which catches inter-sample peaks that the samples themselves miss.
+pub fn true_peak(wave: &Wave) -> f32 {
+    (0..wave.channels())
+        .flat_map(|ch| resample(wave.channel(ch), 4.0))
+        .fold(0.0, |peak, v| peak.max(v.abs()))
+}
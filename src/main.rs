@@ -1,37 +1,206 @@
-use clap::Parser;
+mod archive;
+mod budget;
+mod finish;
+mod flac;
+mod layout;
+mod loops;
+mod loudness;
+mod output;
+mod pitch;
+mod progress;
+mod resample;
+mod space;
+mod stop;
+mod stretch;
+mod wav;
+
+use clap::{ArgAction, CommandFactory, Parser};
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, HashSet},
     f32::consts::PI,
-    fs::OpenOptions,
     io::{Read, Write},
     iter::once,
     mem::replace,
     panic::{catch_unwind, AssertUnwindSafe},
-    sync::{Mutex, OnceLock},
+    sync::{Arc, Mutex, OnceLock, atomic::Ordering::Relaxed},
+    time::{Duration, Instant},
 };
 
+use budget::{Budgets, Limits, Refusal};
+use finish::{Finish, Target};
 use fundsp::{
     hacker::{An, Lowpole},
-    prelude::{U1, U2, resynth},
+    prelude::{AudioUnit, U1, U2, bandpass_hz, highpass_hz, lowpass_hz, notch_hz, resynth},
     wave::Wave,
 };
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use itertools::iproduct;
+use layout::Template;
+use loops::{LoopCut, Seam, loop_stage};
+use output::Sink;
+use progress::{Progress, Totals};
+use rayon::iter::{ParallelBridge, ParallelIterator};
 use sha3::{
     Sha3_256,
     digest::{FixedOutput, Update},
 };
+use space::{Constraints, GroupRule, Space};
+use stop::{Reason, Stop};
+use wav::Format;
 
 // ── Modes ─────────────────────────────────────────────────────────────────────
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 enum Mode {
     Standard,
     Div,
     Atan,
     FreqMult,
     FreqDivNorm,
+    /// Quantise every input to the given bit depth and combine them bitwise.
+    Bitwise(BitOp, u8),
+    /// Crossfade from the first input to the last over the output duration.
+    Morph(Crossfade),
+    /// Like [`Mode::Morph`], but interpolates STFT magnitudes instead of samples.
+    SpectralMorph(Crossfade),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BitOp {
+    Xor,
+    And,
+    Or,
+}
+
+/// Crossfade shape used by the morph modes.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+enum Crossfade {
+    Linear,
+    EqualPower,
+    SCurve,
+}
+
+impl Crossfade {
+    /// Gains for the outgoing and incoming input at crossfade position `f` in `[0, 1]`.
+    fn gains(self, f: f32) -> (f32, f32) {
+        match self {
+            Crossfade::Linear => (1.0 - f, f),
+            Crossfade::EqualPower => ((f * PI / 2.0).cos(), (f * PI / 2.0).sin()),
+            Crossfade::SCurve => {
+                let g = f * f * (3.0 - 2.0 * f);
+                (1.0 - g, g)
+            }
+        }
+    }
+}
+
+/// Implementation of the repeat (`x`) / stride (`s`) ratio stages.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+enum Resampling {
+    /// Sample-and-hold repetition followed by plain decimation.
+    Legacy,
+    /// Band-limited windowed-sinc resampling by the same `x / s` ratio.
+    Sinc,
+}
+
+const BIT_OPS: [BitOp; 3] = [BitOp::Xor, BitOp::And, BitOp::Or];
+
+impl Mode {
+    /// Whether the result does not depend on the order of the inputs (up to
+    /// floating-point rounding).
+    fn is_commutative(self) -> bool {
+        match self {
+            Mode::Standard | Mode::Atan | Mode::FreqMult | Mode::Bitwise(..) => true,
+            Mode::Div | Mode::FreqDivNorm | Mode::Morph(_) | Mode::SpectralMorph(_) => false,
+        }
+    }
+}
+
+/// Names follow the command line: `std`, `atan`, `div`, `freq-mult`,
+/// `freq-div-norm`, `xor:8` (operator and bit depth), `morph:linear` and
+/// `spectral-morph:s-curve` (crossfade curve).
+impl std::fmt::Display for Mode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use clap::ValueEnum;
+        let curve = |c: &Crossfade| c.to_possible_value().unwrap().get_name().to_owned();
+        match self {
+            Mode::Standard => f.write_str("std"),
+            Mode::Div => f.write_str("div"),
+            Mode::Atan => f.write_str("atan"),
+            Mode::FreqMult => f.write_str("freq-mult"),
+            Mode::FreqDivNorm => f.write_str("freq-div-norm"),
+            Mode::Bitwise(op, bits) => {
+                let op = match op {
+                    BitOp::Xor => "xor",
+                    BitOp::And => "and",
+                    BitOp::Or => "or",
+                };
+                write!(f, "{op}:{bits}")
+            }
+            Mode::Morph(c) => write!(f, "morph:{}", curve(c)),
+            Mode::SpectralMorph(c) => write!(f, "spectral-morph:{}", curve(c)),
+        }
+    }
+}
+
+impl std::str::FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use clap::ValueEnum;
+        let (name, arg) = match s.split_once(':') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (s, None),
+        };
+        let bits = || -> Result<u8, String> {
+            let bits: u8 = arg
+                .ok_or(format!("{s}: missing bit depth (e.g. {name}:8)"))?
+                .parse()
+                .map_err(|e| format!("{s}: {e}"))?;
+            if !BIT_DEPTHS.contains(&(bits as i64)) {
+                return Err(format!("{s}: bit depth must be between 4 and 16"));
+            }
+            Ok(bits)
+        };
+        let curve = || -> Result<Crossfade, String> {
+            Crossfade::from_str(arg.unwrap_or("linear"), true).map_err(|e| format!("{s}: {e}"))
+        };
+        Ok(match (name, arg) {
+            ("std", None) => Mode::Standard,
+            ("div", None) => Mode::Div,
+            ("atan", None) => Mode::Atan,
+            ("freq-mult", None) => Mode::FreqMult,
+            ("freq-div-norm", None) => Mode::FreqDivNorm,
+            ("xor", _) => Mode::Bitwise(BitOp::Xor, bits()?),
+            ("and", _) => Mode::Bitwise(BitOp::And, bits()?),
+            ("or", _) => Mode::Bitwise(BitOp::Or, bits()?),
+            ("morph", _) => Mode::Morph(curve()?),
+            ("spectral-morph", _) => Mode::SpectralMorph(curve()?),
+            _ => return Err(format!("{s}: unknown mode")),
+        })
+    }
+}
+
+/// Which values of a boolean per-input flag the search explores.
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+enum Explore {
+    Off,
+    On,
+    Both,
 }
 
+impl Explore {
+    fn values(self) -> &'static [bool] {
+        match self {
+            Explore::Off => &[false],
+            Explore::On => &[true],
+            Explore::Both => &[false, true],
+        }
+    }
+}
+
+/// Bit depths accepted for [`Mode::Bitwise`].
+const BIT_DEPTHS: std::ops::RangeInclusive<i64> = 4..=16;
+
 const MODES: [Mode; 5] = [
     Mode::Standard,
     Mode::Atan,
@@ -42,9 +211,163 @@ const MODES: [Mode; 5] = [
 
 // ── Parameter types ───────────────────────────────────────────────────────────
 
+/// A loaded audio file together with its lazily computed content hash.
+type SourceWave = (Wave, OnceLock<[u8; 32]>);
+
+/// A position or duration within an input, either relative to its length or in
+/// milliseconds. Parsed from `0.25` (fraction) or `250ms`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Span {
+    Frac(f32),
+    Ms(f32),
+}
+
+impl Span {
+    /// Resolve to a sample count for a wave of `len` samples.
+    fn samples(self, len: usize, sample_rate: f64) -> usize {
+        match self {
+            Span::Frac(f) => (f as f64 * len as f64).round() as usize,
+            Span::Ms(ms) => (ms as f64 / 1000.0 * sample_rate).round() as usize,
+        }
+    }
+
+    fn update_hash(self, h: &mut dyn Update) {
+        match self {
+            Span::Frac(f) => h.update(&f.to_ne_bytes()),
+            Span::Ms(ms) => {
+                h.update(b"ms");
+                h.update(&ms.to_ne_bytes());
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Span::Frac(v) => write!(f, "{v}"),
+            Span::Ms(ms) => write!(f, "{ms}ms"),
+        }
+    }
+}
+
+impl std::str::FromStr for Span {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (v, ms) = match s.strip_suffix("ms") {
+            Some(v) => (v, true),
+            None => (s, false),
+        };
+        let v: f32 = v.trim().parse().map_err(|e| format!("{s}: {e}"))?;
+        if !(v >= 0.0 && v.is_finite()) || (!ms && v > 1.0) {
+            return Err(format!(
+                "{s}: expected a fraction in [0, 1] or a duration like 250ms"
+            ));
+        }
+        // Every spelling of zero (`0ms`, `-0`) selects the same samples, so they
+        // all become the default start and hash alike.
+        Ok(match v {
+            0.0 => Span::Frac(0.0),
+            _ if ms => Span::Ms(v),
+            _ => Span::Frac(v),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FilterKind {
+    Lowpass,
+    Highpass,
+    Bandpass,
+    Notch,
+}
+
+/// A state-variable filter applied to an input before combination. Parsed from
+/// `kind:cutoff[:q]` with kind one of `lp`, `hp`, `bp`, `notch`, e.g. `lp:800:0.7`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Filter {
+    kind: FilterKind,
+    /// Cutoff (or centre) frequency in Hz.
+    cutoff: f32,
+    q: f32,
+}
+
+impl Filter {
+    fn apply(self, samples: &[f32], sample_rate: f64) -> Vec<f32> {
+        let mut node: Box<dyn AudioUnit> = match self.kind {
+            FilterKind::Lowpass => Box::new(lowpass_hz(self.cutoff, self.q)),
+            FilterKind::Highpass => Box::new(highpass_hz(self.cutoff, self.q)),
+            FilterKind::Bandpass => Box::new(bandpass_hz(self.cutoff, self.q)),
+            FilterKind::Notch => Box::new(notch_hz(self.cutoff, self.q)),
+        };
+        let mut tmp = Wave::new(0, sample_rate);
+        tmp.push_channel(samples);
+        tmp = tmp.filter_latency(tmp.duration(), &mut *node);
+        tmp.channel(0).clone()
+    }
+
+    /// Whether the filter is stable at `sample_rate`, i.e. its cutoff lies
+    /// below the Nyquist frequency.
+    fn fits(self, sample_rate: f64) -> bool {
+        (self.cutoff as f64) < sample_rate / 2.0
+    }
+
+    fn update_hash(self, h: &mut dyn Update) {
+        h.update(match self.kind {
+            FilterKind::Lowpass => b"lp" as &[u8],
+            FilterKind::Highpass => b"hp",
+            FilterKind::Bandpass => b"bp",
+            FilterKind::Notch => b"notch",
+        });
+        h.update(&self.cutoff.to_ne_bytes());
+        h.update(&self.q.to_ne_bytes());
+    }
+}
+
+impl std::fmt::Display for Filter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.kind {
+            FilterKind::Lowpass => "lp",
+            FilterKind::Highpass => "hp",
+            FilterKind::Bandpass => "bp",
+            FilterKind::Notch => "notch",
+        };
+        write!(f, "{kind}:{}:{}", self.cutoff, self.q)
+    }
+}
+
+impl std::str::FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let kind = match parts.next().unwrap_or("") {
+            "lp" => FilterKind::Lowpass,
+            "hp" => FilterKind::Highpass,
+            "bp" => FilterKind::Bandpass,
+            "notch" => FilterKind::Notch,
+            k => {
+                return Err(format!(
+                    "{s}: unknown filter kind `{k}` (lp, hp, bp, notch)"
+                ));
+            }
+        };
+        let cutoff = positive_f32(parts.next().ok_or(format!("{s}: missing cutoff"))?)?;
+        let q = parts.next().map(positive_f32).transpose()?.unwrap_or(0.707);
+        if parts.next().is_some() {
+            return Err(format!("{s}: expected kind:cutoff[:q]"));
+        }
+        Ok(Filter { kind, cutoff, q })
+    }
+}
+
 /// Parameters for one audio input in a merge operation.
+#[derive(Clone, Copy)]
 struct InputSpec<'a> {
-    wave: &'a (Wave, OnceLock<[u8; 32]>),
+    wave: &'a SourceWave,
+    /// Where the wave was loaded from, for recipes.
+    path: &'a std::path::Path,
     /// Each sample is repeated `x` times before striding.
     x: usize,
     /// Keep every `s`-th sample after repeating.
@@ -53,6 +376,42 @@ struct InputSpec<'a> {
     om: bool,
     /// Reverse sample playback order (play the audio backwards).
     rev: bool,
+    /// Where the used window of the input begins.
+    start: Span,
+    /// Length of the used window; clipped to the end of the input.
+    len: Span,
+    /// Mix weight: a plain gain, or an exponent in the product modes.
+    gain: f32,
+    /// Optional band-limiting of the input before combination.
+    filter: Option<Filter>,
+    /// Pitch shift in cents, applied by band-limited resampling.
+    pitch: i32,
+    /// Duration factor applied without changing pitch.
+    stretch: f32,
+}
+
+impl InputSpec<'_> {
+    /// Sample range of the input selected by `start` and `len`.
+    fn window(&self) -> std::ops::Range<usize> {
+        let (wave, _) = self.wave;
+        let lo = self
+            .start
+            .samples(wave.len(), wave.sample_rate())
+            .min(wave.len());
+        let hi = lo + self.len.samples(wave.len(), wave.sample_rate());
+        lo..hi.min(wave.len())
+    }
+
+    /// Resampling ratio implementing the pitch shift (output samples per input sample).
+    fn pitch_ratio(&self) -> f64 {
+        2f64.powf(-self.pitch as f64 / 1200.0)
+    }
+
+    /// Length of the input once windowed, pitch-shifted and stretched, before the
+    /// `x`/`s` stage.
+    fn source_len(&self) -> usize {
+        (self.window().len() as f64 * self.pitch_ratio() * self.stretch as f64).round() as usize
+    }
 }
 
 struct MergeParams<'a> {
@@ -62,38 +421,104 @@ struct MergeParams<'a> {
     /// Result stride.
     rs: usize,
     mode: Mode,
+    /// How the `x`/`s` and `rx`/`rs` ratios are applied.
+    resampling: Resampling,
+    finish: Finish,
 }
 
 // ── Hashing ───────────────────────────────────────────────────────────────────
 
+/// Collects hash input as raw bytes, for ordering inputs by what they hash to.
+struct HashBytes(Vec<u8>);
+
+impl Update for HashBytes {
+    fn update(&mut self, data: &[u8]) {
+        self.0.extend_from_slice(data);
+    }
+}
+
+impl InputSpec<'_> {
+    /// Hash this input as the `i`-th of a merge.
+    fn update_hash(&self, i: usize, h: &mut dyn Update) {
+        let (w, cache) = self.wave;
+        h.update(cache.get_or_init(|| {
+            catch_unwind(AssertUnwindSafe(|| {
+                let mut bytes = Vec::new();
+                let _ = w.write_wav16(&mut bytes);
+                let mut sha = Sha3_256::default();
+                sha.update(&bytes);
+                sha.finalize_fixed().into()
+            }))
+            .unwrap_or([0u8; 32])
+        }));
+        // Encode (x, s) with a position tag so different input orderings produce
+        // different hashes.
+        for (j, &v) in [self.x, self.s].iter().enumerate() {
+            if v != 1 {
+                h.update(&usize::to_ne_bytes(i * 2 + j));
+                h.update(&usize::to_ne_bytes(v));
+            }
+        }
+        if self.om {
+            h.update(&[i as u8, b'o', b'm']);
+        }
+        if self.rev {
+            h.update(&[i as u8, b'r', b'e', b'v']);
+        }
+        // Window, tagged like the flags; the full-length default adds nothing.
+        if self.start != Span::Frac(0.0) {
+            h.update(&[i as u8, b's', b't']);
+            self.start.update_hash(h);
+        }
+        if self.len != Span::Frac(1.0) {
+            h.update(&[i as u8, b'l', b'e', b'n']);
+            self.len.update_hash(h);
+        }
+        if self.gain != 1.0 {
+            h.update(&[i as u8, b'g']);
+            h.update(&self.gain.to_ne_bytes());
+        }
+        if let Some(filter) = self.filter {
+            h.update(&[i as u8, b'f']);
+            filter.update_hash(h);
+        }
+        if self.pitch != 0 {
+            h.update(&[i as u8, b'p']);
+            h.update(&self.pitch.to_ne_bytes());
+        }
+        if self.stretch != 1.0 {
+            h.update(&[i as u8, b't', b's']);
+            h.update(&self.stretch.to_ne_bytes());
+        }
+    }
+
+    /// Position-independent sort key: the source content hash followed by the
+    /// encoded parameters.
+    fn canonical_key(&self) -> Vec<u8> {
+        let mut bytes = HashBytes(Vec::new());
+        self.update_hash(0, &mut bytes);
+        bytes.0
+    }
+}
+
 impl MergeParams<'_> {
+    /// Put the inputs of a commutative mode into canonical order, so every
+    /// permutation of the same inputs renders identically.
+    fn canonicalize(&mut self) {
+        if self.mode.is_commutative() {
+            self.inputs.sort_by_cached_key(InputSpec::canonical_key);
+        }
+    }
+
     fn update_hash(&self, h: &mut dyn Update) {
-        for (i, inp) in self.inputs.iter().enumerate() {
-            let (w, cache) = inp.wave;
-            h.update(cache.get_or_init(|| {
-                catch_unwind(AssertUnwindSafe(|| {
-                    let mut bytes = Vec::new();
-                    let _ = w.write_wav16(&mut bytes);
-                    let mut sha = Sha3_256::default();
-                    sha.update(&bytes);
-                    sha.finalize_fixed().into()
-                }))
-                .unwrap_or([0u8; 32])
-            }));
-            // Encode (x, s) with a position tag so different input orderings produce
-            // different hashes.
-            for (j, &v) in [inp.x, inp.s].iter().enumerate() {
-                if v != 1 {
-                    h.update(&usize::to_ne_bytes(i * 2 + j));
-                    h.update(&usize::to_ne_bytes(v));
-                }
-            }
-            if inp.om {
-                h.update(&[i as u8, b'o', b'm']);
-            }
-            if inp.rev {
-                h.update(&[i as u8, b'r', b'e', b'v']);
-            }
+        // Commutative modes hash their inputs in canonical order, so all
+        // permutations share one hash (that of the canonical permutation).
+        let mut inputs: Vec<&InputSpec> = self.inputs.iter().collect();
+        if self.mode.is_commutative() {
+            inputs.sort_by_cached_key(|inp| inp.canonical_key());
+        }
+        for (i, inp) in inputs.into_iter().enumerate() {
+            inp.update_hash(i, h);
         }
         // Result (rx, rs), tagged to distinguish from per-input params.
         for (j, &v) in [self.rx, self.rs].iter().enumerate() {
@@ -109,7 +534,53 @@ impl MergeParams<'_> {
             Mode::Div => b"div",
             Mode::FreqMult => b"freqmult",
             Mode::FreqDivNorm => b"freqdivnorm",
+            Mode::Bitwise(BitOp::Xor, _) => b"xor",
+            Mode::Bitwise(BitOp::And, _) => b"and",
+            Mode::Bitwise(BitOp::Or, _) => b"or",
+            Mode::Morph(_) => b"morph",
+            Mode::SpectralMorph(_) => b"smorph",
         });
+        match self.mode {
+            Mode::Bitwise(_, bits) => h.update(&[bits]),
+            Mode::Morph(curve) | Mode::SpectralMorph(curve) => h.update(match curve {
+                Crossfade::Linear => b"lin" as &[u8],
+                Crossfade::EqualPower => b"pow",
+                Crossfade::SCurve => b"scurve",
+            }),
+            _ => {}
+        }
+        // Legacy resampling adds nothing so existing hashes stay valid.
+        if self.resampling == Resampling::Sinc {
+            h.update(b"sinc");
+        }
+        // Likewise, the default final stage adds nothing.
+        if let Some(seam) = self.finish.seam {
+            h.update(b"loop");
+            match seam {
+                Seam::ZeroCrossing => h.update(b"zc"),
+                Seam::Crossfade(ms) => {
+                    h.update(b"xf");
+                    h.update(&ms.to_ne_bytes());
+                }
+            }
+        }
+        if let Some(rate) = self.finish.sample_rate {
+            h.update(b"sr");
+            h.update(&rate.to_ne_bytes());
+        }
+        if self.finish.target != Target::Peak(0.0) {
+            let (tag, level): (&[u8], f32) = match self.finish.target {
+                Target::Peak(db) => (b"peak", db),
+                Target::Rms(db) => (b"rms", db),
+                Target::Lufs(lufs) => (b"lufs", lufs),
+            };
+            h.update(tag);
+            h.update(&level.to_ne_bytes());
+        }
+        if let Some(ceiling) = self.finish.ceiling {
+            h.update(b"tp");
+            h.update(&ceiling.to_ne_bytes());
+        }
     }
 
     fn compute_hash(&self) -> String {
@@ -119,16 +590,113 @@ impl MergeParams<'_> {
     }
 }
 
+// ── Recipes ───────────────────────────────────────────────────────────────────
+
+impl InputSpec<'_> {
+    /// The source path, its repeat/stride pair and every other parameter that
+    /// differs from its default, in command-line syntax, e.g.
+    /// `kick.wav x=2 s=3 rev pitch=+700c`.
+    fn describe(&self) -> String {
+        let mut d = self.path.display().to_string();
+        d += &format!(" x={} s={}", self.x, self.s);
+        if self.om {
+            d += " om";
+        }
+        if self.rev {
+            d += " rev";
+        }
+        if self.start != Span::Frac(0.0) {
+            d += &format!(" start={}", self.start);
+        }
+        if self.len != Span::Frac(1.0) {
+            d += &format!(" len={}", self.len);
+        }
+        if self.gain != 1.0 {
+            d += &format!(" gain={}", self.gain);
+        }
+        if let Some(filter) = self.filter {
+            d += &format!(" filter={filter}");
+        }
+        if self.pitch != 0 {
+            d += &format!(" pitch={:+}c", self.pitch);
+        }
+        if self.stretch != 1.0 {
+            d += &format!(" stretch={}", self.stretch);
+        }
+        d
+    }
+}
+
+impl MergeParams<'_> {
+    /// Everything needed to reproduce this merge, as metadata fields. `INPUT`
+    /// repeats once per input, in merge order.
+    fn recipe(&self, hash: &str) -> Vec<(&'static str, String)> {
+        let mut fields = vec![
+            (
+                "GENERATOR",
+                concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")).to_owned(),
+            ),
+            ("HASH", hash.to_owned()),
+            ("MODE", self.mode.to_string()),
+            ("RX", self.rx.to_string()),
+            ("RS", self.rs.to_string()),
+        ];
+        if self.resampling != Resampling::Legacy {
+            fields.push(("RESAMPLING", "sinc".to_owned()));
+        }
+        if let Some(seam) = self.finish.seam {
+            fields.push(("LOOP", seam.to_string()));
+        }
+        if let Some(rate) = self.finish.sample_rate {
+            fields.push(("SAMPLE_RATE", rate.to_string()));
+        }
+        if self.finish.target != Target::Peak(0.0) {
+            fields.push(("NORMALIZE", self.finish.target.to_string()));
+        }
+        if let Some(ceiling) = self.finish.ceiling {
+            fields.push(("TRUE_PEAK", ceiling.to_string()));
+        }
+        fields.extend(self.inputs.iter().map(|inp| ("INPUT", inp.describe())));
+        fields
+    }
+}
+
 // ── Sample helpers ────────────────────────────────────────────────────────────
 
-/// Extract amplitude-normalised, om-applied samples for one channel of one input.
-fn input_channel_samples(inp: &InputSpec, amp: f32, channel: usize, mode: Mode) -> Vec<f32> {
+/// Extract amplitude-normalised, filtered, pitch-shifted, stretched, om- and
+/// gain-applied samples for one channel of one input.
+fn input_channel_samples(
+    inp: &InputSpec,
+    amp: f32,
+    channel: usize,
+    mode: Mode,
+    resampling: Resampling,
+) -> Vec<f32> {
     let (wave, _) = inp.wave;
-    (0..wave.len())
-        .map(|p| wave.at(channel, if inp.rev { wave.len() - p - 1 } else { p }) / amp)
-        .flat_map(|s| once(s).cycle().take(inp.x))
-        .enumerate()
-        .filter_map(|(i, s)| if i % inp.s == 0 { Some(s) } else { None })
+    let window = inp.window();
+    let mut samples: Vec<f32> = window
+        .clone()
+        .map(|p| {
+            let p = if inp.rev {
+                window.end - 1 - (p - window.start)
+            } else {
+                p
+            };
+            wave.at(channel, p) / amp
+        })
+        .collect();
+    if let Some(filter) = inp.filter {
+        samples = filter.apply(&samples, wave.sample_rate());
+    }
+    if inp.pitch != 0 {
+        // Raising the pitch means fewer samples at the same sample rate.
+        samples = resample::resample(&samples, inp.pitch_ratio());
+    }
+    if inp.stretch != 1.0 {
+        samples = stretch::stretch(&samples, inp.stretch as f64, wave.sample_rate());
+    }
+    rescale(samples, inp.x, inp.s, resampling)
+        .into_iter()
         .map(|s| {
             if inp.om {
                 match mode {
@@ -139,9 +707,74 @@ fn input_channel_samples(inp: &InputSpec, amp: f32, channel: usize, mode: Mode)
                 s
             }
         })
+        .map(|s| match mode {
+            _ if inp.gain == 1.0 => s,
+            // Scaling one factor of a product only changes the overall level, which
+            // normalisation undoes, so product modes weight by exponent instead.
+            Mode::Standard | Mode::FreqMult => s.abs().powf(inp.gain) * s.signum(),
+            // Weighted in the tangent domain when combined, so clipping can't
+            // push a sample past the pole and flip its sign.
+            Mode::Atan => s,
+            _ => s * inp.gain,
+        })
         .collect()
 }
 
+/// Stretch a sequence by the ratio `x / s`.
+fn rescale(samples: Vec<f32>, x: usize, s: usize, resampling: Resampling) -> Vec<f32> {
+    match resampling {
+        _ if x == s => samples,
+        Resampling::Legacy => samples
+            .into_iter()
+            .flat_map(|v| once(v).cycle().take(x))
+            .enumerate()
+            .filter_map(|(i, v)| if i % s == 0 { Some(v) } else { None })
+            .collect(),
+        Resampling::Sinc => resample::resample(&samples, x as f64 / s as f64),
+    }
+}
+
+/// Loop the combined sequence and stretch it by `rx / rs`, up to `take_len` samples.
+/// The legacy path repeats and strides the looped stream itself, so the stride
+/// phase carries across loop boundaries exactly as it always has.
+fn result_stage(
+    seq: Vec<f32>,
+    rx: usize,
+    rs: usize,
+    take_len: usize,
+    resampling: Resampling,
+) -> Vec<f32> {
+    match resampling {
+        Resampling::Legacy => seq
+            .iter()
+            .copied()
+            .cycle()
+            .flat_map(|s| once(s).cycle().take(rx))
+            .enumerate()
+            .filter_map(|(i, s)| if i % rs == 0 { Some(s) } else { None })
+            .take(take_len)
+            .collect(),
+        Resampling::Sinc => rescale(seq, rx, rs, resampling)
+            .into_iter()
+            .cycle()
+            .take(take_len)
+            .collect(),
+    }
+}
+
+/// Quantise a sample in `[-1, 1]` to a signed `bits`-bit integer.
+fn quantise(s: f32, bits: u8) -> i32 {
+    let max = ((1i32 << (bits - 1)) - 1) as f32;
+    (s.clamp(-1.0, 1.0) * max).round() as i32
+}
+
+/// Inverse of [`quantise`]. Bitwise operators preserve sign extension, so `q`
+/// only needs rescaling; the one extra negative code is clamped to `-1.0`.
+fn dequantise(q: i32, bits: u8) -> f32 {
+    let max = ((1i32 << (bits - 1)) - 1) as f32;
+    (q as f32 / max).max(-1.0)
+}
+
 /// Combine two sample sequences in the frequency domain (FreqMult / FreqDivNorm).
 /// For N > 2 inputs this is the binary operation in a left-fold across all inputs.
 fn freq_combine_pair(a: Vec<f32>, b: Vec<f32>, mode: Mode, sample_rate: f64) -> Vec<f32> {
@@ -178,13 +811,75 @@ fn freq_combine_pair(a: Vec<f32>, b: Vec<f32>, mode: Mode, sample_rate: f64) ->
     (0..tmp.len()).map(|i| tmp.at(0, i)).collect()
 }
 
+/// Locate output sample `i` of `len` within an `n`-input morph: returns the index
+/// of the outgoing input and the crossfade position towards the next one.
+fn morph_position(i: usize, len: usize, n: usize) -> (usize, f32) {
+    let pos = i as f32 / (len.max(2) - 1) as f32 * (n - 1) as f32;
+    let k = (pos as usize).min(n - 2);
+    (k, (pos - k as f32).clamp(0.0, 1.0))
+}
+
+/// Morph across all inputs in the frequency domain: magnitudes are crossfaded with
+/// `curve` and the phase is taken from whichever input currently dominates.
+/// Each adjacent pair is resynthesised over the full length and only its own
+/// segment of the timeline is kept.
+fn spectral_morph(seqs: Vec<Vec<f32>>, curve: Crossfade, sample_rate: f64) -> Vec<f32> {
+    let n = seqs.len();
+    let min_len = seqs.iter().map(|s| s.len()).min().unwrap_or(0);
+    let mut out = vec![0.0f32; min_len];
+    for k in 0..n - 1 {
+        let mut tmp = Wave::new(2, sample_rate);
+        for (&a, &b) in seqs[k].iter().zip(&seqs[k + 1]) {
+            tmp.push((a, b));
+        }
+        tmp = tmp.filter_latency(
+            tmp.duration(),
+            &mut resynth::<U2, U1, _>(256, |w| {
+                let i = (w.time() * sample_rate).max(0.0) as usize;
+                let (seg, f) = morph_position(i, min_len, n);
+                let f = match seg.cmp(&k) {
+                    std::cmp::Ordering::Less => 1.0,
+                    std::cmp::Ordering::Equal => f,
+                    std::cmp::Ordering::Greater => 0.0,
+                };
+                let (ga, gb) = curve.gains(f);
+                for i in 0..w.bins() {
+                    let (a, b) = (w.at(0, i), w.at(1, i));
+                    let mag = ga * a.norm() + gb * b.norm();
+                    let phase = if gb > ga { b.arg() } else { a.arg() };
+                    w.set(0, i, fundsp::prelude::Complex32::from_polar(mag, phase));
+                }
+            }),
+        );
+        for (i, o) in out.iter_mut().enumerate() {
+            if morph_position(i, min_len, n).0 == k {
+                *o = tmp.at(0, i);
+            }
+        }
+    }
+    out
+}
+
+/// Reject combinations where all (x, s) pairs — including the result's (rx, rs) —
+/// are uniformly contracting or uniformly expanding.
+fn ratios_ok(mut pairs: impl Iterator<Item = (usize, usize)> + Clone) -> bool {
+    !pairs.clone().all(|(vx, vs)| vs * 2 > vx) && !pairs.all(|(vx, vs)| vx * 2 > vs)
+}
+
 // ── Core merge ────────────────────────────────────────────────────────────────
 
 fn merge(params: MergeParams) -> Option<Wave> {
     if params.inputs.len() < 2 {
         return None;
     }
-    let MergeParams { inputs, rx, rs, mode } = params;
+    let MergeParams {
+        inputs,
+        rx,
+        rs,
+        mode,
+        resampling,
+        finish,
+    } = params;
 
     // All inputs must share channel count and sample rate; reject degenerate 2-sample waves.
     let channels = inputs[0].wave.0.channels();
@@ -199,30 +894,23 @@ fn merge(params: MergeParams) -> Option<Wave> {
         if inp.wave.0.len() == 2 {
             return None;
         }
+        if inp.window().is_empty() {
+            return None;
+        }
     }
 
     // Pre-check that every input wave has non-zero amplitude.
     let amplitudes: Vec<f32> = inputs.iter().map(|inp| inp.wave.0.amplitude()).collect();
-    if amplitudes.iter().any(|&a| a == 0.0) {
+    if amplitudes.contains(&0.0) {
         return None;
     }
 
-    // Reject combinations where all (x, s) pairs — including the result's (rx, rs) —
-    // are uniformly contracting or uniformly expanding.
-    let all_xs: Vec<(usize, usize)> = inputs
-        .iter()
-        .map(|i| (i.x, i.s))
-        .chain(once((rx, rs)))
-        .collect();
-    if all_xs.iter().all(|&(vx, vs)| vs * 2 > vx) {
-        return None;
-    }
-    if all_xs.iter().all(|&(vx, vs)| vx * 2 > vs) {
+    if !ratios_ok(inputs.iter().map(|i| (i.x, i.s)).chain(once((rx, rs)))) {
         return None;
     }
 
     // Maximum samples to generate: shortest input length × a bounded scale factor.
-    let min_input_len = inputs.iter().map(|i| i.wave.0.len()).min().unwrap_or(0);
+    let min_input_len = inputs.iter().map(|i| i.source_len()).min().unwrap_or(0);
     let max_param = inputs
         .iter()
         .flat_map(|i| [i.x, i.s])
@@ -233,18 +921,34 @@ fn merge(params: MergeParams) -> Option<Wave> {
     let take_len = min_input_len * max_param;
 
     let mut new_wave = Wave::new(0, sample_rate);
+    let mut cut = None;
 
     'channel: for ch in 0..channels {
         // Sample sequences for every input on this channel.
         let input_seqs: Vec<Vec<f32>> = inputs
             .iter()
             .zip(&amplitudes)
-            .map(|(inp, &amp)| input_channel_samples(inp, amp, ch, mode))
+            .map(|(inp, &amp)| input_channel_samples(inp, amp, ch, mode, resampling))
             .collect();
 
-        // Combine all input sequences according to the current mode.
+        // A morph runs once over the whole output, so its inputs are looped and
+        // rescaled to the output length first and the morph itself is never
+        // cycled.
+        let morph = matches!(mode, Mode::Morph(_) | Mode::SpectralMorph(_));
+        let (input_seqs, stage_rx, stage_rs) = if morph {
+            let seqs = input_seqs
+                .into_iter()
+                .map(|seq| result_stage(seq, rx, rs, take_len, resampling))
+                .collect();
+            (seqs, 1, 1)
+        } else {
+            (input_seqs, rx, rs)
+        };
+
+        // Combine all input sequences according to the current mode, then loop and
+        // rescale the result.
         let combined: Vec<f32> = match mode {
-            Mode::Standard | Mode::Atan | Mode::Div => {
+            Mode::Standard | Mode::Atan | Mode::Div | Mode::Bitwise(..) | Mode::Morph(_) => {
                 let min_len = input_seqs.iter().map(|s| s.len()).min().unwrap_or(0);
                 (0..min_len)
                     .map(|i| {
@@ -252,8 +956,11 @@ fn merge(params: MergeParams) -> Option<Wave> {
                         match mode {
                             Mode::Standard => vals.iter().copied().product(),
                             Mode::Atan => {
-                                let c: f32 =
-                                    vals.iter().map(|&v| (v * PI / 2.0).tan()).sum();
+                                let c: f32 = vals
+                                    .iter()
+                                    .zip(&inputs)
+                                    .map(|(&v, inp)| inp.gain * (v * PI / 2.0).tan())
+                                    .sum();
                                 if c.is_infinite() || c.is_nan() {
                                     0.0
                                 } else {
@@ -277,38 +984,68 @@ fn merge(params: MergeParams) -> Option<Wave> {
                                     })
                                 })
                                 .unwrap_or(0.0),
+                            Mode::Bitwise(op, bits) => {
+                                let q = vals
+                                    .iter()
+                                    .map(|&v| quantise(v, bits))
+                                    .reduce(|a, b| match op {
+                                        BitOp::Xor => a ^ b,
+                                        BitOp::And => a & b,
+                                        BitOp::Or => a | b,
+                                    })
+                                    .unwrap_or(0);
+                                dequantise(q, bits)
+                            }
+                            Mode::Morph(curve) => {
+                                let (k, f) = morph_position(i, min_len, vals.len());
+                                let (ga, gb) = curve.gains(f);
+                                ga * vals[k] + gb * vals[k + 1]
+                            }
                             _ => unreachable!(),
                         }
                     })
-                    .cycle()
-                    .flat_map(|s| once(s).cycle().take(rx))
-                    .enumerate()
-                    .filter_map(|(i, s)| if i % rs == 0 { Some(s) } else { None })
-                    .take(take_len)
                     .collect()
             }
 
             Mode::FreqMult | Mode::FreqDivNorm => {
                 // Left-fold all inputs pairwise in the frequency domain.
-                let folded = input_seqs
+                input_seqs
                     .into_iter()
-                    .reduce(|a, b| freq_combine_pair(a, b, mode, sample_rate))?;
-                folded
-                    .into_iter()
-                    .cycle()
-                    .flat_map(|s| once(s).cycle().take(rx))
-                    .enumerate()
-                    .filter_map(|(i, s)| if i % rs == 0 { Some(s) } else { None })
-                    .take(take_len)
-                    .collect()
+                    .reduce(|a, b| freq_combine_pair(a, b, mode, sample_rate))?
             }
+
+            Mode::SpectralMorph(curve) => spectral_morph(input_seqs, curve, sample_rate),
+        };
+        let combined = match finish.seam {
+            Some(seam) => {
+                let cut = *cut.get_or_insert_with(|| LoopCut::find(&combined, seam, sample_rate));
+                loop_stage(
+                    cut.apply(&combined),
+                    stage_rx,
+                    stage_rs,
+                    take_len,
+                    resampling,
+                )
+            }
+            None => result_stage(combined, stage_rx, stage_rs, take_len, resampling),
         };
 
         // ── Post-processing (identical to the original per-channel pipeline) ──
 
+        // An input that diverged (e.g. through an unstable filter) spoils the
+        // whole merge; trimming would otherwise hide its NaNs.
+        if combined.iter().any(|s| !s.is_finite()) {
+            return None;
+        }
         let mut samples = combined;
         let mut tmp = Wave::new(0, sample_rate);
-        tmp.push_channel(&samples);
+        // A loop is filtered as if it had already been playing: its tail runs
+        // through the filters first and is dropped again afterwards.
+        let preroll = match finish.seam {
+            Some(_) => samples.len().min(64),
+            None => 0,
+        };
+        tmp.push_channel(&[&samples[samples.len() - preroll..], &samples].concat());
         let threshold = 0.05;
         tmp = tmp.filter_latency(tmp.duration(), &mut An(Lowpole::<f32, U1>::new(8000.0f32)));
         for i in 0..tmp.len() {
@@ -323,13 +1060,17 @@ fn merge(params: MergeParams) -> Option<Wave> {
         }
         tmp.normalize();
         tmp = tmp.filter_latency(tmp.duration(), &mut An(Lowpole::<f32, U1>::new(8000.0f32)));
-        samples = (0..tmp.len()).map(|i| tmp.at(0, i)).collect();
+        samples = (preroll..tmp.len()).map(|i| tmp.at(0, i)).collect();
 
-        for _ in 0..(samples.len() / 3) {
-            let Some(p) = samples.pop() else { break };
-            if p.abs() > threshold {
-                samples.push(p);
-                break;
+        // Loops keep their length: trimming would cut the last period short.
+        let trim = finish.seam.is_none();
+        if trim {
+            for _ in 0..(samples.len() / 3) {
+                let Some(p) = samples.pop() else { break };
+                if p.abs() > threshold {
+                    samples.push(p);
+                    break;
+                }
             }
         }
 
@@ -361,11 +1102,12 @@ fn merge(params: MergeParams) -> Option<Wave> {
             continue 'channel;
         }
 
-        loop {
-            let Some(p) = samples.pop() else { break };
-            if p.abs() > threshold {
-                samples.push(p);
-                break;
+        if trim {
+            while let Some(p) = samples.pop() {
+                if p.abs() > threshold {
+                    samples.push(p);
+                    break;
+                }
             }
         }
 
@@ -385,11 +1127,10 @@ fn merge(params: MergeParams) -> Option<Wave> {
         new_wave.push_channel(&samples);
     }
 
-    if new_wave.channels() == 0 {
+    if new_wave.channels() == 0 || new_wave.is_empty() {
         return None;
     }
-    new_wave.normalize();
-    Some(new_wave)
+    Some(finish.apply(new_wave))
 }
 
 // ── Zip loading ───────────────────────────────────────────────────────────────
@@ -400,7 +1141,7 @@ fn merge(params: MergeParams) -> Option<Wave> {
 fn load_from_zip_bytes(
     virtual_base: &std::path::Path,
     bytes: Vec<u8>,
-    waves: &mut BTreeMap<std::path::PathBuf, (Wave, OnceLock<[u8; 32]>)>,
+    waves: &mut BTreeMap<std::path::PathBuf, SourceWave>,
 ) -> std::io::Result<()> {
     let cursor = std::io::Cursor::new(bytes);
     let mut archive = zip::ZipArchive::new(cursor)
@@ -426,12 +1167,12 @@ fn load_from_zip_bytes(
             let _ = load_from_zip_bytes(&virtual_path, entry_bytes, waves);
         } else {
             let suffix = format!(".{ext}");
-            if let Ok(mut tmp) = tempfile::Builder::new().suffix(&suffix).tempfile() {
-                if tmp.write_all(&entry_bytes).is_ok() && tmp.flush().is_ok() {
-                    if let Ok(w) = Wave::load(tmp.path()) {
-                        waves.insert(virtual_path, (w, OnceLock::new()));
-                    }
-                }
+            if let Ok(mut tmp) = tempfile::Builder::new().suffix(&suffix).tempfile()
+                && tmp.write_all(&entry_bytes).is_ok()
+                && tmp.flush().is_ok()
+                && let Ok(w) = Wave::load(tmp.path())
+            {
+                waves.insert(virtual_path, (w, OnceLock::new()));
                 // tmp dropped here → temp file deleted
             }
         }
@@ -439,25 +1180,259 @@ fn load_from_zip_bytes(
     Ok(())
 }
 
+// ── Output ────────────────────────────────────────────────────────────────────
+
+/// File format of the outputs.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+enum Codec {
+    /// RIFF WAVE.
+    Wav,
+    /// Lossless FLAC, with the recipe as Vorbis comments.
+    Flac,
+}
+
+impl Codec {
+    fn extension(self) -> &'static str {
+        match self {
+            Codec::Wav => "wav",
+            Codec::Flac => "flac",
+        }
+    }
+}
+
+/// Print the recipe embedded in each of `paths`.
+fn inspect(paths: &[std::path::PathBuf]) -> std::io::Result<()> {
+    for path in paths {
+        let bytes = std::fs::read(path)?;
+        let recipe = wav::read_recipe(&bytes).or_else(|| flac::read_comments(&bytes));
+        println!("{}:", path.display());
+        match recipe {
+            Some(fields) => {
+                for (key, value) in fields {
+                    println!("  {key}={value}");
+                }
+            }
+            None => println!("  (no recipe)"),
+        }
+    }
+    Ok(())
+}
+
+// ── Argument parsing ──────────────────────────────────────────────────────────
+
+/// Clap value parser for strictly positive, finite floats.
+fn positive_f32(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(v) if v > 0.0 && v.is_finite() => Ok(v),
+        Ok(_) => Err(format!("{s}: expected a positive number")),
+        Err(e) => Err(format!("{s}: {e}")),
+    }
+}
+
+/// Clap value parser for non-zero counts.
+fn positive_usize(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(0) => Err(format!("{s}: expected a positive integer")),
+        Ok(v) => Ok(v),
+        Err(e) => Err(format!("{s}: {e}")),
+    }
+}
+
+/// Clap value parser for a pitch shift given in semitones, returned in cents.
+fn semitones_to_cents(s: &str) -> Result<i32, String> {
+    match s.parse::<f64>() {
+        Ok(v) if v.abs() <= 48.0 => Ok((v * 100.0).round() as i32),
+        Ok(_) => Err(format!("{s}: pitch shifts are limited to ±48 semitones")),
+        Err(e) => Err(format!("{s}: {e}")),
+    }
+}
+
+/// Clap value parser for durations: seconds, or a number with an `s`, `m` or `h`
+/// suffix.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (num, unit) = match s.char_indices().last() {
+        Some((i, c @ ('s' | 'm' | 'h'))) => (&s[..i], c),
+        _ => (s, 's'),
+    };
+    let scale = match unit {
+        'h' => 3600.0,
+        'm' => 60.0,
+        _ => 1.0,
+    };
+    match num.parse::<f64>() {
+        Ok(v) if v >= 0.0 && v.is_finite() => Ok(Duration::from_secs_f64(v * scale)),
+        _ => Err(format!(
+            "{s}: expected a duration such as 90, 90s, 15m or 2h"
+        )),
+    }
+}
+
+/// Clap value parser for `KEY=COUNT` quotas.
+fn parse_quota<K: std::str::FromStr>(s: &str) -> Result<(K, u64), String>
+where
+    K::Err: std::fmt::Display,
+{
+    let (key, count) = s
+        .rsplit_once('=')
+        .ok_or(format!("{s}: expected KEY=COUNT"))?;
+    let key = key.parse().map_err(|e| format!("{s}: {e}"))?;
+    let count = count.parse().map_err(|e| format!("{s}: {e}"))?;
+    Ok((key, count))
+}
+
+/// Clap value parser for `NAME=PATH` input groups.
+fn parse_group(s: &str) -> Result<(String, std::path::PathBuf), String> {
+    match s.split_once('=') {
+        Some((name, path)) if !name.is_empty() && !path.is_empty() => {
+            Ok((name.to_owned(), path.into()))
+        }
+        _ => Err(format!("{s}: expected NAME=PATH")),
+    }
+}
+
+/// Prepend the options from the `--config` TOML file (if any) to `args` as
+/// ordinary command-line arguments, so that the explicit ones, which come later,
+/// override them.
+fn with_config_args(mut args: Vec<std::ffi::OsString>) -> std::io::Result<Vec<std::ffi::OsString>> {
+    let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+    let path = args.iter().enumerate().find_map(|(i, a)| {
+        let a = a.to_str()?;
+        match a.strip_prefix("--config") {
+            Some("") => args.get(i + 1).cloned(),
+            Some(v) => v.strip_prefix('=').map(Into::into),
+            None => None,
+        }
+    });
+    let Some(path) = path else {
+        return Ok(args);
+    };
+    let text = std::fs::read_to_string(&path)?;
+    let table: toml::Table = text
+        .parse()
+        .map_err(|e| invalid(format!("{}: {e}", path.display())))?;
+
+    let scalar = |key: &str, v: &toml::Value| -> std::io::Result<String> {
+        match v {
+            toml::Value::String(s) => Ok(s.clone()),
+            toml::Value::Integer(i) => Ok(i.to_string()),
+            toml::Value::Float(f) => Ok(f.to_string()),
+            _ => Err(invalid(format!(
+                "{}: unsupported value for `{key}`",
+                path.display()
+            ))),
+        }
+    };
+    let mut extra: Vec<std::ffi::OsString> = Vec::new();
+    let mut positional: Vec<std::ffi::OsString> = Vec::new();
+    for (key, value) in &table {
+        let flag = format!("--{}", key.replace('_', "-"));
+        match value {
+            toml::Value::Boolean(true) => extra.push(flag.into()),
+            toml::Value::Boolean(false) => {}
+            toml::Value::Array(items) if key == "inputs" => {
+                for item in items {
+                    positional.push(scalar(key, item)?.into());
+                }
+            }
+            // Group paths may contain commas, so each gets a flag of its own.
+            toml::Value::Array(items) if key == "group" => {
+                for item in items {
+                    extra.push(format!("{flag}={}", scalar(key, item)?).into());
+                }
+            }
+            toml::Value::Array(items) => {
+                let items: Vec<String> = items
+                    .iter()
+                    .map(|item| scalar(key, item))
+                    .collect::<Result<_, _>>()?;
+                extra.push(format!("{flag}={}", items.join(",")).into());
+            }
+            v => extra.push(format!("{flag}={}", scalar(key, v)?).into()),
+        }
+    }
+    let rest = args.split_off(1.min(args.len()));
+    args.extend(extra);
+    args.extend(positional);
+    args.extend(rest);
+    Ok(args)
+}
+
 // ── Entry point ───────────────────────────────────────────────────────────────
 
 fn main() -> Result<(), std::io::Error> {
-    let mut waves: BTreeMap<std::path::PathBuf, (Wave, OnceLock<[u8; 32]>)> = BTreeMap::new();
+    let mut waves: BTreeMap<std::path::PathBuf, SourceWave> = BTreeMap::new();
 
+    /// Every option can also be set in a TOML file passed with `--config`, keyed
+    /// by its long name (e.g. `xs = [1, 2, 3]`, `spectral-morph = true`). Options
+    /// given on the command line take precedence; `inputs = ["./kits"]` adds to
+    /// the input paths given on the command line.
     #[derive(Parser)]
+    #[command(args_override_self = true)]
     struct Opt {
+        /// TOML file with default values for any of these options
+        #[arg(long, value_name = "FILE")]
+        config: Option<std::path::PathBuf>,
+
         /// Output directory
-        #[arg(short, long)]
-        out: String,
+        #[arg(short, long, required_unless_present = "inspect")]
+        out: Option<String>,
+
+        /// Print the recipe embedded in each INPUT file (WAV or FLAC) and exit
+        #[arg(long)]
+        inspect: bool,
 
         /// Maximum output size in MB
         #[arg(short, long)]
         max_size: Option<usize>,
 
+        /// Maximum number of output files
+        #[arg(long)]
+        max_files: Option<u64>,
+
+        /// Maximum run time, in seconds or with a unit (e.g. 90s, 15m, 2h)
+        #[arg(long, value_parser = parse_duration)]
+        max_time: Option<Duration>,
+
+        /// Maximum number of outputs per mode, as MODE=COUNT
+        #[arg(long, value_delimiter = ',', action = ArgAction::Set, value_parser = parse_quota::<Mode>)]
+        mode_quota: Vec<(Mode, u64)>,
+
+        /// Maximum number of outputs per number of inputs, as N=COUNT
+        #[arg(long, value_delimiter = ',', action = ArgAction::Set, value_parser = parse_quota::<usize>)]
+        n_quota: Vec<(usize, u64)>,
+
         /// Hash prefix filter
         #[arg(long, default_value = "")]
         pow: String,
 
+        /// Don't report progress on stderr
+        #[arg(long)]
+        no_progress: bool,
+
+        /// Sample encoding of the outputs; integer formats are dithered. With
+        /// FLAC, `auto` picks 24-bit where WAV would use float
+        #[arg(long, value_enum, default_value_t = Format::Auto)]
+        format: Format,
+
+        /// File format of the outputs
+        #[arg(long, value_enum, default_value_t = Codec::Wav)]
+        codec: Codec,
+
+        // clap reads `{n}` anywhere in help text as a line break, so the
+        // placeholders are listed without their braces.
+        /// Names of the outputs below the output directory, e.g.
+        /// `{mode}/{hash}`. Placeholders go in braces: hash, hash:N (first N
+        /// digits), mode, n (number of inputs), srcK_stem (file stem of input
+        /// K), channels and duration_bucket. The name must contain {hash}, and
+        /// the extension is appended
+        #[arg(long, default_value = "{hash:4}/{hash}")]
+        layout: Template,
+
+        /// Pack the outputs into rolling zip archives of at most this many GiB
+        /// each, instead of one file per output under the output directory
+        #[arg(long, value_name = "GIB", value_parser = positive_f32)]
+        archive: Option<f32>,
+
         /// Minimum number of audio inputs per merge (≥ 2)
         #[arg(long, default_value_t = 2)]
         min_inputs: usize,
@@ -466,20 +1441,224 @@ fn main() -> Result<(), std::io::Error> {
         #[arg(long, default_value_t = 2)]
         max_inputs: usize,
 
+        /// Never merge a source file with itself (same as
+        /// `--min-distinct-sources 2`)
+        #[arg(long)]
+        distinct_sources: bool,
+
+        /// Minimum number of distinct source files per merge
+        #[arg(long, default_value_t = 1)]
+        min_distinct_sources: usize,
+
+        /// Combination modes to explore: std, atan, freq-mult, div, freq-div-norm,
+        /// xor:BITS, and:BITS, or:BITS, morph:CURVE, spectral-morph:CURVE
+        #[arg(long, value_delimiter = ',', action = ArgAction::Set, default_values_t = MODES)]
+        modes: Vec<Mode>,
+
+        /// Per-input repeat factors (`x`) to explore
+        #[arg(
+            long,
+            value_delimiter = ',',
+            action = ArgAction::Set,
+            default_value = "1,2,3,5",
+            value_parser = positive_usize
+        )]
+        xs: Vec<usize>,
+
+        /// Per-input strides (`s`) to explore
+        #[arg(
+            long,
+            value_delimiter = ',',
+            action = ArgAction::Set,
+            default_value = "1,2,3,5",
+            value_parser = positive_usize
+        )]
+        ss: Vec<usize>,
+
+        /// Result repeat factors (`rx`) to explore
+        #[arg(
+            long,
+            value_delimiter = ',',
+            action = ArgAction::Set,
+            default_value = "1,2,3,5",
+            value_parser = positive_usize
+        )]
+        rxs: Vec<usize>,
+
+        /// Result strides (`rs`) to explore
+        #[arg(
+            long,
+            value_delimiter = ',',
+            action = ArgAction::Set,
+            default_value = "1,2,3,5",
+            value_parser = positive_usize
+        )]
+        rss: Vec<usize>,
+
+        /// Also explore repeat/stride pairs with equal non-unity values, which
+        /// only differ from 1:1 in how they loop
+        #[arg(long)]
+        keep_equal_pairs: bool,
+
+        /// Which values of the per-input one-minus inversion to explore
+        #[arg(long, value_enum, default_value_t = Explore::Both)]
+        om: Explore,
+
+        /// Which values of the per-input reversal to explore
+        #[arg(long, value_enum, default_value_t = Explore::Both)]
+        rev: Explore,
+
+        /// Bit depths to explore with the XOR/AND/OR modes (4–16); the bitwise
+        /// modes are skipped entirely unless at least one depth is given
+        #[arg(
+            long,
+            value_delimiter = ',',
+            action = ArgAction::Set,
+            value_parser = clap::value_parser!(u8).range(BIT_DEPTHS)
+        )]
+        bit_depths: Vec<u8>,
+
+        /// Crossfade curves to explore with the morph mode; morphing is skipped
+        /// unless at least one curve is given
+        #[arg(long, value_delimiter = ',', action = ArgAction::Set)]
+        morph: Vec<Crossfade>,
+
+        /// Also explore the spectral variant of every enabled morph curve
+        #[arg(long)]
+        spectral_morph: bool,
+
+        /// Window start positions to explore per input, as fractions of the
+        /// input length (`0.5`) or milliseconds (`500ms`)
+        #[arg(long, value_delimiter = ',', action = ArgAction::Set, default_value = "0")]
+        starts: Vec<Span>,
+
+        /// Window lengths to explore per input, as fractions of the input
+        /// length (`0.25`) or milliseconds (`250ms`)
+        #[arg(long, value_delimiter = ',', action = ArgAction::Set, default_value = "1")]
+        lens: Vec<Span>,
+
+        /// Per-input gains to explore (used as exponents in the product modes
+        /// and as weights of the tangents in atan mode)
+        #[arg(
+            long,
+            value_delimiter = ',',
+            action = ArgAction::Set,
+            default_value = "1",
+            value_parser = positive_f32
+        )]
+        gains: Vec<f32>,
+
+        /// Per-input filters to explore in addition to no filter, as
+        /// `kind:cutoff[:q]` with kind one of lp, hp, bp, notch (e.g. `lp:800:0.7`)
+        #[arg(long, value_delimiter = ',', action = ArgAction::Set)]
+        filters: Vec<Filter>,
+
+        /// Per-input pitch shifts to explore, in semitones; fractional values
+        /// select cents (e.g. `-12,7,0.5`)
+        #[arg(
+            long,
+            value_delimiter = ',',
+            action = ArgAction::Set,
+            default_value = "0",
+            value_parser = semitones_to_cents,
+            allow_hyphen_values = true
+        )]
+        pitches: Vec<i32>,
+
+        /// How the repeat/stride ratios are applied; sinc avoids the aliasing of
+        /// the legacy sample-and-hold path
+        #[arg(long, value_enum, default_value_t = Resampling::Legacy)]
+        resampling: Resampling,
+
+        /// Render outputs as seamless loops, cut at matching rising zero
+        /// crossings (`zero-crossing`) or with the seam crossfaded over MS
+        /// milliseconds (`crossfade:MS`), and tag WAV outputs with a `smpl`
+        /// chunk holding the loop and the detected root note
+        #[arg(long = "loop", value_name = "SEAM")]
+        seam: Option<Seam>,
+
+        /// Sample rate of the outputs in Hz, converted with band-limited sinc
+        /// resampling (default: that of the inputs)
+        #[arg(long, value_parser = clap::value_parser!(u32).range(8000..=384000))]
+        sample_rate: Option<u32>,
+
+        /// Level every output is normalised to: `peak:DB` (sample peak in dBFS),
+        /// `rms:DB` (dBFS) or `lufs:LUFS` (integrated loudness, ITU-R BS.1770)
+        #[arg(long, default_value = "peak:0", allow_hyphen_values = true)]
+        normalize: Target,
+
+        /// True-peak ceiling in dBTP that normalisation never exceeds; without
+        /// it, rms and lufs targets stop at a sample peak of 0 dBFS
+        #[arg(long, value_name = "DBTP", allow_hyphen_values = true)]
+        true_peak: Option<f32>,
+
+        /// Per-input time-stretch factors to explore (duration multipliers that
+        /// keep the pitch, e.g. `1,2,0.5`)
+        #[arg(
+            long,
+            value_delimiter = ',',
+            action = ArgAction::Set,
+            default_value = "1",
+            value_parser = positive_f32
+        )]
+        stretches: Vec<f32>,
+
+        /// Input paths belonging to a named group, as NAME=PATH; repeat the flag
+        /// for more paths (positional inputs form their own unnamed group)
+        #[arg(long, value_name = "NAME=PATH", value_parser = parse_group)]
+        group: Vec<(String, std::path::PathBuf)>,
+
+        /// Which groups every merge must draw from: `any`, `each` (exactly one
+        /// input per group) or a number N (inputs from at least N groups)
+        #[arg(long, default_value = "any")]
+        group_rule: GroupRule,
+
         /// Input paths (files or directories)
         #[arg(value_name = "INPUT")]
         inputs: Vec<std::path::PathBuf>,
     }
 
-    let opts = Opt::parse();
-    let out = opts.out;
+    let opts = Opt::parse_from(with_config_args(std::env::args_os().collect())?);
+    if opts.codec == Codec::Flac && opts.format == Format::Float32 {
+        Opt::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "FLAC stores integer samples only; use --format pcm16, pcm24 or auto",
+            )
+            .exit();
+    }
+    if opts.inspect {
+        return inspect(&opts.inputs);
+    }
+    let out = opts.out.expect("clap requires --out without --inspect");
     let pow = opts.pow;
     let min_inputs = opts.min_inputs.max(2);
     let max_inputs = opts.max_inputs.max(min_inputs);
+    let min_distinct = opts
+        .min_distinct_sources
+        .max(if opts.distinct_sources { 2 } else { 1 });
 
     // ── Load waves ────────────────────────────────────────────────────────────
 
-    for input in opts.inputs.iter() {
+    // Positional inputs form the unnamed group 0; `--group` names are numbered
+    // from 1 in order of first appearance.
+    let mut group_names: Vec<&str> = vec![""];
+    let mut roots: Vec<(usize, &std::path::PathBuf)> =
+        opts.inputs.iter().map(|path| (0, path)).collect();
+    for (name, path) in &opts.group {
+        let g = match group_names.iter().position(|n| n == name) {
+            Some(g) => g,
+            None => {
+                group_names.push(name);
+                group_names.len() - 1
+            }
+        };
+        roots.push((g, path));
+    }
+    let mut group_of: BTreeMap<std::path::PathBuf, usize> = BTreeMap::new();
+
+    for (group, input) in roots {
+        let mut loaded = BTreeMap::new();
         for entry in walkdir::WalkDir::new(input) {
             let entry = entry?;
             if entry.file_type().is_file() {
@@ -491,136 +1670,362 @@ fn main() -> Result<(), std::io::Error> {
                     .to_ascii_lowercase();
                 if ext == "zip" {
                     if let Ok(bytes) = std::fs::read(&path) {
-                        let _ = load_from_zip_bytes(&path, bytes, &mut waves);
+                        let _ = load_from_zip_bytes(&path, bytes, &mut loaded);
                     }
                 } else if let Ok(w) = Wave::load(&path) {
-                    waves.insert(path, (w, OnceLock::new()));
+                    loaded.insert(path, (w, OnceLock::new()));
                 }
             }
         }
+        for (path, w) in loaded {
+            if let Some(other) = group_of.insert(path.clone(), group)
+                && other != group
+            {
+                let name = |g: usize| match group_names[g] {
+                    "" => "the positional inputs".to_owned(),
+                    name => format!("group `{name}`"),
+                };
+                Opt::command()
+                    .error(
+                        clap::error::ErrorKind::ArgumentConflict,
+                        format!(
+                            "{} is in both {} and {}",
+                            path.display(),
+                            name(other),
+                            name(group)
+                        ),
+                    )
+                    .exit();
+            }
+            waves.insert(path, w);
+        }
+    }
+    if opts.codec == Codec::Flac {
+        waves.retain(|path, (w, _)| {
+            let fits = w.channels() <= flac::MAX_CHANNELS;
+            if !fits {
+                eprintln!(
+                    "skipping {}: FLAC holds at most {} channels",
+                    path.display(),
+                    flac::MAX_CHANNELS
+                );
+            }
+            fits
+        });
     }
 
     // ── Build search space ────────────────────────────────────────────────────
 
-    let size = opts.max_size.map(|a| Mutex::new(a * 1024 * 1024));
+    let budgets = Budgets::new(Limits {
+        max_bytes: opts.max_size.map(|mb| mb as u64 * 1024 * 1024),
+        max_files: opts.max_files,
+        max_time: opts.max_time,
+        mode_quotas: opts.mode_quota.clone(),
+        n_quotas: opts.n_quota.clone(),
+    });
 
-    // (x, s) combinations: repeat × stride, excluding identical non-unity pairs.
-    let xsi: Vec<(usize, usize)> = [1usize, 2, 3, 5]
-        .into_iter()
-        .flat_map(|a| {
-            [1, 2, 3, 5]
-                .into_iter()
-                .filter(move |b| *b != a || *b == 1)
-                .map(move |b| (a, b))
-        })
-        .collect();
+    // (x, s) combinations: repeat × stride, excluding identical non-unity pairs
+    // unless asked to keep them.
+    let ratio_pairs = |xs: &[usize], ss: &[usize]| -> Vec<(usize, usize)> {
+        iproduct!(xs, ss)
+            .filter(|&(a, b)| a != b || *b == 1 || opts.keep_equal_pairs)
+            .map(|(&a, &b)| (a, b))
+            .collect()
+    };
+    let xsi = ratio_pairs(&opts.xs, &opts.ss);
+    let rxsi = ratio_pairs(&opts.rxs, &opts.rss);
 
-    // All possible per-wave-slot parameter combinations: (wave_ref, x, s, om, rev).
-    // This flattens the wave × xsi × {om,rev} product into a single indexed list so
-    // we can address any combination with a single u64 index below.
-    let per_wave: Vec<(&(Wave, OnceLock<[u8; 32]>), usize, usize, bool, bool)> = waves
-        .values()
-        .flat_map(|w| {
-            xsi.iter().flat_map(move |&(x, s)| {
-                [(false, false), (false, true), (true, false), (true, true)]
-                    .into_iter()
-                    .map(move |(om, rev)| (w, x, s, om, rev))
-            })
-        })
-        .collect();
+    // All possible per-input parameter combinations except the (x, s) pair, which
+    // the search space draws from `xsi` itself so it can skip ratio combinations
+    // that `merge` would reject. Waves that can never merge are left out here.
+    // Each source is paired with the group of the file it comes from.
+    for (path, (wave, _)) in &waves {
+        for filter in opts.filters.iter().filter(|f| !f.fits(wave.sample_rate())) {
+            eprintln!(
+                "skipping filter {filter} for {}: the cutoff must lie below {} Hz",
+                path.display(),
+                wave.sample_rate() / 2.0
+            );
+        }
+    }
+    let sources: Vec<(InputSpec, usize)> = iproduct!(
+        waves
+            .iter()
+            .filter(|(_, (w, _))| w.len() != 2 && w.amplitude() != 0.0)
+            .map(|(path, wave)| (wave, path, group_of[path])),
+        iproduct!(opts.om.values(), opts.rev.values()),
+        &opts.starts,
+        &opts.lens,
+        &opts.gains,
+        once(None).chain(opts.filters.iter().copied().map(Some)),
+        &opts.pitches,
+        &opts.stretches
+    )
+    .map(
+        |((wave, path, group), (&om, &rev), &start, &len, &gain, filter, &pitch, &stretch)| {
+            let spec = InputSpec {
+                wave,
+                path,
+                x: 1,
+                s: 1,
+                om,
+                rev,
+                start,
+                len,
+                gain,
+                filter,
+                pitch,
+                stretch,
+            };
+            (spec, group)
+        },
+    )
+    .filter(|(spec, _)| spec.filter.is_none_or(|f| f.fits(spec.wave.0.sample_rate())))
+    .collect();
 
-    // All possible shared parameter combinations: (rx, rs, mode).
-    let shared: Vec<(usize, usize, Mode)> = xsi
+    let mut groups: Vec<usize> = group_of.values().copied().collect();
+    groups.sort();
+    groups.dedup();
+    let constraints = Constraints {
+        min_distinct,
+        group_rule: opts.group_rule,
+        groups,
+    };
+
+    // Enabled modes: the requested list plus one bitwise mode per (operator, depth)
+    // and one morph mode per requested curve.
+    let mut modes: Vec<Mode> = Vec::new();
+    for mode in opts
+        .modes
         .iter()
-        .flat_map(|&(rx, rs)| MODES.iter().map(move |&mode| (rx, rs, mode)))
-        .collect();
+        .copied()
+        .chain(
+            opts.bit_depths
+                .iter()
+                .flat_map(|&bits| BIT_OPS.into_iter().map(move |op| Mode::Bitwise(op, bits))),
+        )
+        .chain(opts.morph.iter().map(|&curve| Mode::Morph(curve)))
+        .chain(
+            opts.morph
+                .iter()
+                .filter(|_| opts.spectral_morph)
+                .map(|&curve| Mode::SpectralMorph(curve)),
+        )
+    {
+        if !modes.contains(&mode) {
+            modes.push(mode);
+        }
+    }
 
-    let nk = per_wave.len() as u64; // per-slot choice count
-    let ns = shared.len() as u64;   // shared-param choice count
+    let finish = Finish {
+        seam: opts.seam,
+        sample_rate: opts.sample_rate,
+        target: opts.normalize,
+        ceiling: opts.true_peak,
+    };
 
     // ── Iterate over all n-input merges ───────────────────────────────────────
 
-    for n in min_inputs..=max_inputs {
-        // nk^n is the number of ordered n-tuples of per-wave slots.
-        // Skip this n if the count overflows u64 (would only happen for enormous
-        // wave libraries combined with very large n).
-        let Some(wave_combos) = nk.checked_pow(n as u32) else {
-            break;
-        };
-        let Some(total) = wave_combos.checked_mul(ns) else {
-            break;
-        };
+    let sink = match opts.archive {
+        Some(gib) => {
+            let cap = (gib as f64 * 1024.0 * 1024.0 * 1024.0) as u64;
+            Sink::Archives(Box::new(archive::Archives::open(out.as_ref(), cap)?))
+        }
+        None => Sink::Tree(out.into()),
+    };
+    // Names that depend on the rendered output are unknown until it is
+    // rendered, so such layouts look existing outputs up by hash instead.
+    let seen: Option<Mutex<HashSet<String>>> = if opts.layout.needs_output() {
+        let names = sink.names()?;
+        let hashes = names.iter().filter_map(|name| opts.layout.hash_in(name, opts.codec.extension()));
+        Some(Mutex::new(hashes.map(str::to_owned).collect()))
+    } else {
+        None
+    };
 
-        (0..total)
-            .into_par_iter()
-            .map(|idx| {
-                // Decode the flat index into shared params + n per-wave slots.
-                // Layout: idx = wave_combo_idx + shared_idx * wave_combos
-                let si = (idx / wave_combos) as usize;
-                let wci = idx % wave_combos;
-                let (rx, rs, mode) = shared[si];
-
-                // Decode wci as an n-digit number in base nk (little-endian digits).
-                // Digit i selects the per-wave-slot for input i.
-                let inputs: Vec<InputSpec> = (0..n)
-                    .map(|i| {
-                        // nk^i fits in u64 because nk^n fits (checked above) and i < n.
-                        let slot_i =
-                            ((wci / nk.saturating_pow(i as u32)) % nk) as usize;
-                        let (wave, x, s, om, rev) = per_wave[slot_i];
-                        InputSpec { wave, x, s, om, rev }
-                    })
-                    .collect();
+    let start = Instant::now();
+    let stop = Arc::new(Stop::default());
+    if let Err(e) = stop.on_signal() {
+        eprintln!("cannot handle signals, interrupting will abort the run: {e}");
+    }
+    let mut totals = Totals::default();
+    // Input counts that can produce merges at all; once the quotas admit no
+    // enabled mode at any of them, the run is over.
+    let sizes: Vec<usize> = (min_inputs..=max_inputs)
+        .filter(|&n| constraints.allows_size(n))
+        .collect();
+    let keep_going = || {
+        if budgets.timed_out() {
+            stop.request(Reason::Budget("time"));
+        }
+        !stop.requested()
+    };
 
-                // Record max input duration before inputs are moved into MergeParams.
-                let max_input_duration: f64 = inputs
-                    .iter()
-                    .map(|inp| inp.wave.0.duration())
-                    .fold(f64::NEG_INFINITY, f64::max);
+    // Renders one merge and writes it out unless it is filtered or already present.
+    let process = |params: MergeParams, progress: &Progress| -> std::io::Result<()> {
+        // Record max input duration before params are moved into merge.
+        let max_input_duration: f64 = params
+            .inputs
+            .iter()
+            .map(|inp| inp.window().len() as f64 / inp.wave.0.sample_rate())
+            .fold(f64::NEG_INFINITY, f64::max);
 
-                let params = MergeParams { inputs, rx, rs, mode };
-                let h = params.compute_hash();
-                if !h.starts_with(&pow) {
-                    return Ok(());
-                }
+        let (mode, n) = (params.mode, params.inputs.len());
+        if !budgets.admits(mode, n) {
+            return Ok(());
+        }
+
+        let h = params.compute_hash();
+        if !h.starts_with(&pow) {
+            return Ok(());
+        }
+
+        let template = opts.layout.inputs(&h, &params);
+        let exists = match template.name() {
+            Some(name) => sink.contains(&format!("{name}.{}", opts.codec.extension()))?,
+            None => seen
+                .as_ref()
+                .is_some_and(|s| s.lock().unwrap().contains(&h)),
+        };
+        if exists {
+            return Ok(());
+        }
 
-                let dir1 = format!("{out}/{}", &h[..4]);
-                if !std::fs::exists(&dir1)? {
-                    std::fs::create_dir(&dir1)?;
+        let recipe = params.recipe(&h);
+
+        let merged = merge(params);
+        let counters = progress.counters();
+        counters.merged.fetch_add(1, Relaxed);
+        if let Some(c) = merged {
+            // `auto` uses 16-bit when the output is substantially longer than
+            // any single input (the extra resolution is lost in the stretching
+            // anyway); otherwise it keeps 32-bit for short outputs.
+            let format = match (opts.format, opts.codec) {
+                (Format::Auto, _) if c.duration() > max_input_duration * 1.4 => Format::Pcm16,
+                (Format::Auto, Codec::Wav) => Format::Float32,
+                (Format::Auto, Codec::Flac) => Format::Pcm24,
+                (format, _) => format,
+            };
+            // Encode in memory first so the exact size can be reserved. The
+            // dither is seeded from the hash, so reruns are bit-identical.
+            let mut seed = [0; 32];
+            hex::decode_to_slice(&h, &mut seed).expect("hash is 64 hex digits");
+            // A looping output loops as a whole; without a clear pitch the root
+            // note is middle C.
+            let sampler = finish.seam.map(|_| wav::Sampler {
+                root_note: pitch::root_note(&c).unwrap_or(60.0),
+                loop_start: 0,
+                loop_end: c.len().saturating_sub(1) as u32,
+            });
+            let bytes = match opts.codec {
+                Codec::Wav => wav::encode(&c, format, seed, &recipe, sampler.as_ref()),
+                Codec::Flac => {
+                    // The de facto loop tags of FLAC players and samplers.
+                    let mut comments = recipe.clone();
+                    if sampler.is_some() {
+                        comments.push(("LOOPSTART", "0".to_owned()));
+                        comments.push(("LOOPLENGTH", c.len().to_string()));
+                    }
+                    flac::encode(
+                        &wav::quantise(&c, format.bits(), seed),
+                        c.channels(),
+                        c.sample_rate().round() as u32,
+                        format.bits(),
+                        &comments,
+                    )?
                 }
-                let path = format!("{dir1}/{h}.wav");
-                if std::fs::exists(&path)? {
+            };
+            let reservation = match budgets.reserve(mode, n, bytes.len() as u64) {
+                Ok(reservation) => reservation,
+                Err(Refusal::Exhausted(reason)) => {
+                    stop.request(reason);
                     return Ok(());
                 }
+                Err(Refusal::Quota) => return Ok(()),
+            };
+            let name = template.output(&c).name().expect("no placeholders left");
+            let name = format!("{name}.{}", opts.codec.extension());
+            let Some(path) = sink.write(&name, &bytes)? else {
+                return Ok(());
+            };
+            if let Some(seen) = &seen {
+                seen.lock().unwrap().insert(h);
+            }
+            reservation.commit();
+            if budgets.files_full() {
+                stop.request(Reason::Budget("file"));
+            } else if budgets.all_full(&modes, &sizes) {
+                stop.request(Reason::Budget("quota"));
+            }
+            counters.written.fetch_add(1, Relaxed);
+            counters.bytes.fetch_add(bytes.len() as u64, Relaxed);
+            progress.output(&path.to_string_lossy());
+        } else {
+            counters.rejected.fetch_add(1, Relaxed);
+        }
 
-                if let Some(c) = merge(params) {
-                    let mut f = OpenOptions::new()
-                        .create(true)
-                        .write(true)
-                        .truncate(true)
-                        .open(&path)?;
-                    // Use 16-bit when the output is substantially longer than any
-                    // single input (the extra resolution is lost in the stretching
-                    // anyway); otherwise keep 32-bit for short outputs.
-                    if c.duration() > max_input_duration * 1.4 {
-                        c.write_wav16(&mut f)?;
-                    } else {
-                        c.write_wav32(&mut f)?;
-                    }
-                    if let Some(sz) = &size {
-                        let mut sz = sz.lock().unwrap();
-                        *sz = sz.saturating_sub(f.metadata()?.len() as usize);
-                        if *sz == 0 {
-                            std::process::exit(0);
-                        }
-                    }
-                    println!("{path}");
-                }
+        Ok(())
+    };
 
-                Ok::<_, std::io::Error>(())
-            })
-            .collect::<Result<(), std::io::Error>>()?;
+    for n in min_inputs..=max_inputs {
+        if stop.requested() {
+            break;
+        }
+        if !constraints.allows_size(n) {
+            eprintln!("skipping {n}-input merges: no merge of {n} inputs meets the constraints");
+            continue;
+        }
+        let space = Space::new(
+            n,
+            &sources,
+            &xsi,
+            &rxsi,
+            &modes,
+            opts.resampling,
+            finish,
+            &constraints,
+        );
+
+        let progress = Progress::start(n, space.len(), budgets.max_bytes(), !opts.no_progress);
+
+        // The space is walked lazily, so its size is unbounded; rayon picks up
+        // chunks as they are generated. Once a stop is requested no new chunk or
+        // merge is started, and none of a pass whose quota is full.
+        let result = space
+            .chunks()
+            .take_while(|_| keep_going() && !budgets.n_full(n))
+            .par_bridge()
+            .try_for_each(|chunk| {
+                let mut processed = 0;
+                let result = chunk
+                    .merges()
+                    .take_while(|_| keep_going())
+                    .inspect(|_| processed += 1)
+                    .try_for_each(|params| process(params, &progress));
+                progress.counters().processed.fetch_add(processed, Relaxed);
+                result
+            });
+        totals.add(progress.counters());
+        drop(progress);
+        if result.is_err() {
+            // Keep what was archived before the failure.
+            sink.finish()?;
+        }
+        result?;
     }
+    sink.finish()?;
 
+    std::io::stdout().flush()?;
+    match stop.reason() {
+        Some(reason) => eprintln!("{reason}: {}", totals.report(start.elapsed())),
+        None => eprintln!("done: {}", totals.report(start.elapsed())),
+    }
+    let budget_report = budgets.report();
+    if !budget_report.is_empty() {
+        eprintln!("budgets: {budget_report}");
+    }
+    if let Some(Reason::Signal) = stop.reason() {
+        std::process::exit(130);
+    }
     Ok(())
 }
@@ -0,0 +1,93 @@
+//! Output storage: a directory tree of files or rolling zip archives.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::archive::Archives;
+
+/// Write a file so that it only ever appears at `path` complete: the contents
+/// go to a temporary file in the same directory, which is synced and then
+/// renamed into place. The directory is synced last, so the rename survives a
+/// crash too.
+///
+/// An interrupted write leaves at most a hidden `.*.tmp` file behind, never a
+/// truncated `path` that later runs would mistake for a finished output.
+fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let mut tmp = tempfile::Builder::new()
+        .prefix(".")
+        .suffix(".tmp")
+        .tempfile_in(dir)?;
+    tmp.write_all(bytes)?;
+    tmp.as_file().sync_all()?;
+    tmp.persist(path)?;
+    fs::File::open(dir)?.sync_all()
+}
+
+/// Where outputs are stored. Output names from the layout template are paths
+/// below the output directory or archive entry names.
+pub enum Sink {
+    Tree(PathBuf),
+    Archives(Box<Archives>),
+}
+
+impl Sink {
+    /// Whether the output `name` was already stored.
+    pub fn contains(&self, name: &str) -> io::Result<bool> {
+        match self {
+            Sink::Tree(out) => fs::exists(out.join(name)),
+            Sink::Archives(archives) => Ok(archives.contains(name)),
+        }
+    }
+
+    /// Names of the outputs stored so far.
+    pub fn names(&self) -> io::Result<Vec<String>> {
+        match self {
+            Sink::Tree(out) => {
+                let mut names = Vec::new();
+                // Nothing is stored before the first write creates `out`.
+                if !fs::exists(out)? {
+                    return Ok(names);
+                }
+                for entry in walkdir::WalkDir::new(out) {
+                    let entry = entry?;
+                    // Skip the temporary files of unfinished writes.
+                    if entry.file_type().is_file()
+                        && !entry.file_name().to_string_lossy().starts_with('.')
+                    {
+                        let name = entry.path().strip_prefix(out).unwrap_or(entry.path());
+                        names.push(name.to_string_lossy().into_owned());
+                    }
+                }
+                Ok(names)
+            }
+            Sink::Archives(archives) => Ok(archives.names()),
+        }
+    }
+
+    /// Store the output `name`, returning where it went, or `None` if it was
+    /// stored meanwhile.
+    pub fn write(&self, name: &str, bytes: &[u8]) -> io::Result<Option<PathBuf>> {
+        match self {
+            Sink::Tree(out) => {
+                let path = out.join(name);
+                fs::create_dir_all(path.parent().expect("names have a directory"))?;
+                write_atomically(&path, bytes)?;
+                Ok(Some(path))
+            }
+            Sink::Archives(archives) => archives.add(name, bytes),
+        }
+    }
+
+    /// Complete whatever is still being written.
+    pub fn finish(&self) -> io::Result<()> {
+        match self {
+            Sink::Tree(_) => Ok(()),
+            Sink::Archives(archives) => archives.finish(),
+        }
+    }
+}
@@ -0,0 +1,57 @@
+//! Root-note detection for sampler metadata.
+//!
+//! The fundamental is estimated with YIN (de Cheveigné & Kawahara, 2002): the
+//! cumulative-mean-normalised difference function of the mono mix is searched
+//! for its first dip below a threshold, refined by parabolic interpolation.
+
+use fundsp::wave::Wave;
+
+/// Lowest and highest fundamental considered, in Hz.
+const MIN_HZ: f64 = 40.0;
+const MAX_HZ: f64 = 2000.0;
+
+/// Dip depth that counts as periodic.
+const THRESHOLD: f64 = 0.15;
+
+/// Longest stretch analysed, in seconds.
+const WINDOW: f64 = 1.0;
+
+/// The root note of `wave` as a fractional MIDI note number (69 is A4 at
+/// 440 Hz), or `None` if it has no clear pitch.
+pub fn root_note(wave: &Wave) -> Option<f64> {
+    let rate = wave.sample_rate();
+    let len = wave.len().min((WINDOW * rate) as usize);
+    let mono: Vec<f64> = (0..len)
+        .map(|i| (0..wave.channels()).map(|ch| wave.at(ch, i) as f64).sum())
+        .collect();
+
+    let min_lag = ((rate / MAX_HZ).floor() as usize).max(2);
+    let max_lag = ((rate / MIN_HZ).ceil() as usize).min(len / 2);
+    if min_lag + 1 >= max_lag {
+        return None;
+    }
+    let width = len - max_lag;
+
+    // d'(τ): the squared difference at lag τ over its running mean.
+    let mut cmnd = vec![1.0; max_lag + 1];
+    let mut sum = 0.0;
+    for lag in 1..=max_lag {
+        let d: f64 = (0..width).map(|i| (mono[i] - mono[i + lag]).powi(2)).sum();
+        sum += d;
+        cmnd[lag] = if sum > 0.0 { d * lag as f64 / sum } else { 1.0 };
+    }
+
+    let mut lag = (min_lag..max_lag).find(|&lag| cmnd[lag] < THRESHOLD)?;
+    while lag + 1 < max_lag && cmnd[lag + 1] < cmnd[lag] {
+        lag += 1;
+    }
+    let (a, b, c) = (cmnd[lag - 1], cmnd[lag], cmnd[lag + 1]);
+    let curvature = a - 2.0 * b + c;
+    let offset = if curvature > 0.0 {
+        (a - c) / (2.0 * curvature)
+    } else {
+        0.0
+    };
+    let hz = rate / (lag as f64 + offset);
+    Some(69.0 + 12.0 * (hz / 440.0).log2())
+}
@@ -0,0 +1,199 @@
+//! Run progress reporting.
+//!
+//! Workers only bump atomic counters; a separate reporter thread samples them
+//! and prints a status line to stderr, redrawn in place on a terminal and as
+//! periodic log lines otherwise.
+
+use std::{
+    io::{IsTerminal, Write},
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicU64, Ordering::Relaxed},
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use num_bigint::BigUint;
+use num_traits::ToPrimitive;
+
+/// Counters shared by the workers of one `n`-input pass.
+#[derive(Default)]
+pub struct Counters {
+    /// Indices of the space visited so far.
+    pub processed: AtomicU64,
+    /// Merges rendered (whether or not they produced output).
+    pub merged: AtomicU64,
+    /// Outputs written.
+    pub written: AtomicU64,
+    /// Merges rejected by `merge`.
+    pub rejected: AtomicU64,
+    /// Bytes written.
+    pub bytes: AtomicU64,
+}
+
+/// Counts over a whole run, for the final report.
+#[derive(Default)]
+pub struct Totals {
+    merged: u64,
+    written: u64,
+    rejected: u64,
+    bytes: u64,
+}
+
+impl Totals {
+    /// Add the counts of a finished pass.
+    pub fn add(&mut self, c: &Counters) {
+        self.merged += c.merged.load(Relaxed);
+        self.written += c.written.load(Relaxed);
+        self.rejected += c.rejected.load(Relaxed);
+        self.bytes += c.bytes.load(Relaxed);
+    }
+
+    /// One-line summary of a run that took `elapsed`.
+    pub fn report(&self, elapsed: Duration) -> String {
+        format!(
+            "{} merges, {} written ({:.1} MiB), {} rejected in {}",
+            self.merged,
+            self.written,
+            self.bytes as f64 / (1024.0 * 1024.0),
+            self.rejected,
+            duration(elapsed),
+        )
+    }
+}
+
+/// A running progress display; stops and prints a final line when dropped.
+pub struct Progress {
+    counters: Arc<Counters>,
+    /// Whether printed paths share the terminal with the status line.
+    shared_terminal: bool,
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    reporter: Option<JoinHandle<()>>,
+}
+
+/// Status line: what is being worked on and how far along it is.
+struct Status {
+    n: usize,
+    total: BigUint,
+    budget: Option<u64>,
+    start: Instant,
+}
+
+/// Human-readable duration.
+fn duration(d: Duration) -> String {
+    let s = d.as_secs();
+    match s {
+        0..60 => format!("{s}s"),
+        60..3600 => format!("{}m{:02}s", s / 60, s % 60),
+        _ => format!("{}h{:02}m", s / 3600, s / 60 % 60),
+    }
+}
+
+impl Status {
+    fn line(&self, c: &Counters) -> String {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let processed = c.processed.load(Relaxed);
+        let total = self.total.to_f64().unwrap_or(f64::INFINITY);
+        let rate = processed as f64 / elapsed.max(1e-3);
+        let mut line = format!(
+            "n={}  {processed}/{} ({:.1}%)  {:.0} merges/s  {} written  {} rejected  {:.1} MiB",
+            self.n,
+            self.total,
+            100.0 * processed as f64 / total.max(1.0),
+            c.merged.load(Relaxed) as f64 / elapsed.max(1e-3),
+            c.written.load(Relaxed),
+            c.rejected.load(Relaxed),
+            c.bytes.load(Relaxed) as f64 / (1024.0 * 1024.0),
+        );
+        if let Some(budget) = self.budget {
+            line += &format!(" of {:.1} MiB", budget as f64 / (1024.0 * 1024.0));
+        }
+        if rate > 0.0 && processed > 0 {
+            let left = (total - processed as f64).max(0.0) / rate;
+            if left < 1e9 {
+                line += &format!("  ETA {}", duration(Duration::from_secs_f64(left)));
+            } else {
+                line += "  ETA never";
+            }
+        }
+        line
+    }
+}
+
+impl Progress {
+    /// Start reporting on a pass over `total` indices of `n`-input merges, with an
+    /// optional size budget in bytes. With `enabled` false nothing is printed.
+    pub fn start(n: usize, total: BigUint, budget: Option<u64>, enabled: bool) -> Self {
+        let counters = Arc::new(Counters::default());
+        let interactive = std::io::stderr().is_terminal();
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let reporter = enabled.then(|| {
+            let status = Status {
+                n,
+                total,
+                budget,
+                start: Instant::now(),
+            };
+            let counters = counters.clone();
+            let stop = stop.clone();
+            let period = if interactive {
+                Duration::from_millis(250)
+            } else {
+                Duration::from_secs(10)
+            };
+            std::thread::spawn(move || {
+                let (lock, cvar) = &*stop;
+                let mut stopped = lock.lock().unwrap();
+                loop {
+                    stopped = cvar.wait_timeout(stopped, period).unwrap().0;
+                    let line = status.line(&counters);
+                    let mut err = std::io::stderr().lock();
+                    let _ = if *stopped {
+                        if interactive {
+                            writeln!(err, "\r\x1b[K{line}")
+                        } else {
+                            writeln!(err, "{line}")
+                        }
+                    } else if interactive {
+                        write!(err, "\r\x1b[K{line}")
+                    } else {
+                        writeln!(err, "{line}")
+                    };
+                    if *stopped {
+                        break;
+                    }
+                }
+            })
+        });
+        Progress {
+            counters,
+            shared_terminal: enabled && interactive && std::io::stdout().is_terminal(),
+            stop,
+            reporter,
+        }
+    }
+
+    /// The counters to update from workers.
+    pub fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    /// Print a written output path to stdout without garbling the status line.
+    pub fn output(&self, path: &str) {
+        if self.shared_terminal {
+            let _ = write!(std::io::stderr(), "\r\x1b[K");
+        }
+        println!("{path}");
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        *self.stop.0.lock().unwrap() = true;
+        self.stop.1.notify_all();
+        if let Some(reporter) = self.reporter.take() {
+            let _ = reporter.join();
+        }
+    }
+}
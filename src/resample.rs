@@ -0,0 +1,110 @@
+//! Band-limited resampling by arbitrary ratios.
+//!
+//! A Kaiser-windowed sinc kernel is tabulated once and linearly interpolated, so
+//! any real ratio can be served from the same table. When the ratio shrinks the
+//! signal the kernel is widened to move its cutoff below the new Nyquist.
+
+use std::sync::OnceLock;
+
+/// Zero crossings of the sinc on each side of the kernel centre.
+const ZERO_CROSSINGS: usize = 16;
+
+/// Table entries per zero crossing.
+const TABLE_STEPS: usize = 256;
+
+/// Kaiser window shape; ~80 dB stop-band attenuation.
+const KAISER_BETA: f64 = 8.0;
+
+/// Zeroth-order modified Bessel function of the first kind (power series).
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let q = x * x / 4.0;
+    for k in 1..64 {
+        term *= q / (k * k) as f64;
+        sum += term;
+        if term < sum * 1e-12 {
+            break;
+        }
+    }
+    sum
+}
+
+fn kernel_table() -> &'static [f32] {
+    static TABLE: OnceLock<Vec<f32>> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let norm = bessel_i0(KAISER_BETA);
+        // One extra entry so interpolation at the very edge stays in bounds.
+        (0..=ZERO_CROSSINGS * TABLE_STEPS + 1)
+            .map(|i| {
+                let x = i as f64 / TABLE_STEPS as f64;
+                if x >= ZERO_CROSSINGS as f64 {
+                    return 0.0;
+                }
+                let sinc = if i == 0 {
+                    1.0
+                } else {
+                    (std::f64::consts::PI * x).sin() / (std::f64::consts::PI * x)
+                };
+                let r = x / ZERO_CROSSINGS as f64;
+                let window = bessel_i0(KAISER_BETA * (1.0 - r * r).sqrt()) / norm;
+                (sinc * window) as f32
+            })
+            .collect()
+    })
+}
+
+/// Windowed-sinc kernel evaluated at `x` zero crossings from its centre.
+fn kernel(table: &[f32], x: f64) -> f32 {
+    let pos = x.abs() * TABLE_STEPS as f64;
+    let i = pos as usize;
+    if i >= ZERO_CROSSINGS * TABLE_STEPS {
+        return 0.0;
+    }
+    let frac = (pos - i as f64) as f32;
+    table[i] + (table[i + 1] - table[i]) * frac
+}
+
+/// Resample `input` so that `ratio` output samples are produced per input sample.
+/// Samples outside the input are treated as silence.
+pub fn resample(input: &[f32], ratio: f64) -> Vec<f32> {
+    if ratio == 1.0 || input.is_empty() {
+        return input.to_vec();
+    }
+    let table = kernel_table();
+    // Cutoff relative to the input Nyquist frequency.
+    let cutoff = ratio.min(1.0);
+    let reach = ZERO_CROSSINGS as f64 / cutoff;
+    let out_len = (input.len() as f64 * ratio).round() as usize;
+    (0..out_len)
+        .map(|j| {
+            let t = j as f64 / ratio;
+            let lo = (t - reach).ceil().max(0.0) as usize;
+            let hi = ((t + reach).floor() as usize).min(input.len() - 1);
+            let acc: f32 = (lo..=hi)
+                .map(|k| input[k] * kernel(table, (t - k as f64) * cutoff))
+                .sum();
+            acc * cutoff as f32
+        })
+        .collect()
+}
+
+/// Like [`resample`], but treats `input` as one period of a looping signal, so
+/// the output wraps around without a seam.
+pub fn resample_cyclic(input: &[f32], ratio: f64) -> Vec<f32> {
+    if ratio == 1.0 || input.is_empty() {
+        return input.to_vec();
+    }
+    // One period on either side stands in for the infinite repetition; only
+    // periods shorter than the kernel's reach come out approximate.
+    let tripled: Vec<f32> = input
+        .iter()
+        .copied()
+        .cycle()
+        .take(3 * input.len())
+        .collect();
+    let out = resample(&tripled, ratio);
+    let start = (input.len() as f64 * ratio).round() as usize;
+    let end = (2.0 * input.len() as f64 * ratio).round() as usize;
+    out[start..end].to_vec()
+}
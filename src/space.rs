@@ -0,0 +1,900 @@
+//! Enumeration of the merge search space.
+//!
+//! Only parameter tuples that can pass the structural checks at the top of
+//! [`merge`](crate::merge) are addressed: every input of a tuple comes from the
+//! same channel-count/sample-rate class, and the repeat/stride pairs (together
+//! with the result pair) are neither uniformly contracting nor uniformly
+//! expanding.
+//!
+//! The space is produced lazily as [`Block`]s, one per class, ratio assignment,
+//! mode kind and [`Draw`] pattern, so it never has to be counted or stored up
+//! front. Merges within a block are addressed by dense big-integer indices and
+//! handed out in [`Chunk`]s for parallel processing, so arbitrarily large
+//! spaces can be walked.
+//!
+//! Merges in commutative modes are enumerated as multisets: the (ratio, source)
+//! pairs of the inputs are non-decreasing, so each set of inputs appears once
+//! rather than once per permutation.
+//!
+//! A tuple's sources are picked file by file. The draw pattern of a block fixes
+//! how many distinct source files the tuple uses, which inputs share one and
+//! which input group each file comes from, so tuples with too few distinct
+//! files or the wrong mix of groups (see [`Constraints`]) are never addressed
+//! at all.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    iter::successors,
+    ops::Range,
+    sync::Arc,
+};
+
+use itertools::Itertools;
+use num_bigint::BigUint;
+
+use crate::{Finish, InputSpec, MergeParams, Mode, Resampling, SourceWave, ratios_ok};
+
+/// Which input groups a merge must draw from.
+#[derive(Clone, Copy, Debug)]
+pub enum GroupRule {
+    /// No restriction.
+    Any,
+    /// Exactly one input from every group.
+    Each,
+    /// Inputs from at least this many different groups.
+    AtLeast(usize),
+}
+
+impl std::str::FromStr for GroupRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "any" => Ok(GroupRule::Any),
+            "each" => Ok(GroupRule::Each),
+            _ => s
+                .parse()
+                .map(GroupRule::AtLeast)
+                .map_err(|_| format!("{s}: expected `any`, `each` or a number of groups")),
+        }
+    }
+}
+
+/// Restrictions on which sources may be merged together.
+pub struct Constraints {
+    /// Minimum number of distinct source files per merge.
+    pub min_distinct: usize,
+    pub group_rule: GroupRule,
+    /// Groups that have at least one source, sorted.
+    pub groups: Vec<usize>,
+}
+
+impl Constraints {
+    /// Whether any `n`-input merge can satisfy the constraints.
+    pub fn allows_size(&self, n: usize) -> bool {
+        n >= self.min_distinct
+            && match self.group_rule {
+                GroupRule::Any => true,
+                GroupRule::Each => n == self.groups.len(),
+                GroupRule::AtLeast(k) => n >= k && self.groups.len() >= k,
+            }
+    }
+
+    /// Whether a merge of sources from the given groups is allowed.
+    fn allows(&self, groups: &[usize]) -> bool {
+        let mut groups = groups.to_vec();
+        groups.sort();
+        match self.group_rule {
+            GroupRule::Any => true,
+            GroupRule::Each => groups == self.groups,
+            GroupRule::AtLeast(k) => {
+                groups.dedup();
+                groups.len() >= k
+            }
+        }
+    }
+}
+
+/// Merges per [`Chunk`].
+const CHUNK: u64 = 256;
+
+/// The sources of one file.
+struct File<'a> {
+    /// One source per combination of per-input parameters that leaves a
+    /// non-empty window.
+    sources: Vec<InputSpec<'a>>,
+    /// Input group of the file.
+    group: usize,
+}
+
+/// The files of one (channels, sample rate) class; only a class can merge
+/// together.
+struct Class<'a> {
+    /// Sorted by group, then by their number of sources.
+    files: Vec<File<'a>>,
+    /// Ranges of `files` with the same group and equally many sources.
+    buckets: Vec<Range<usize>>,
+}
+
+impl Class<'_> {
+    /// Number of sources of every file in bucket `b`.
+    fn width(&self, b: usize) -> usize {
+        self.files[self.buckets[b].start].sources.len()
+    }
+
+    /// Group of every file in bucket `b`.
+    fn group(&self, b: usize) -> usize {
+        self.files[self.buckets[b].start].group
+    }
+}
+
+/// Part of a block's draw pattern: `count` distinct files of one bucket, each
+/// of which provides `shape[r]` inputs to run `r`.
+#[derive(Clone, Debug, PartialEq)]
+struct Draw {
+    bucket: usize,
+    count: usize,
+    shape: Vec<usize>,
+}
+
+/// All `n`-input merges over a set of sources.
+pub struct Space<'a> {
+    n: usize,
+    classes: Vec<Class<'a>>,
+    /// Per-input repeat/stride pairs.
+    ratios: &'a [(usize, usize)],
+    /// Result repeat/stride pairs.
+    results: &'a [(usize, usize)],
+    /// Modes that depend on input order, enumerated over ordered tuples.
+    ordered: Vec<Mode>,
+    /// Commutative modes, enumerated over multisets.
+    commutative: Vec<Mode>,
+    resampling: Resampling,
+    finish: Finish,
+    constraints: &'a Constraints,
+}
+
+/// The merges of a [`Space`] that share a class, a ratio assignment, a kind of
+/// mode and a draw pattern.
+pub struct Block<'s, 'a> {
+    space: &'s Space<'a>,
+    class: usize,
+    /// Index into `ratios` of each input's repeat/stride pair.
+    ratios: Vec<usize>,
+    /// Index into `results` of the result pair.
+    result: usize,
+    /// Whether the block holds the commutative modes.
+    commutative: bool,
+    /// Lengths of the runs of inputs whose sources form one multiset: the runs
+    /// of equal ratio pairs for commutative modes, single inputs otherwise.
+    runs: Vec<usize>,
+    draws: Vec<Draw>,
+    len: BigUint,
+}
+
+/// Up to [`CHUNK`] consecutive merges of a [`Block`].
+pub struct Chunk<'s, 'a> {
+    block: Arc<Block<'s, 'a>>,
+    start: BigUint,
+    len: u64,
+}
+
+/// Binomial coefficient.
+fn binomial(n: usize, k: usize) -> BigUint {
+    if k > n {
+        return BigUint::ZERO;
+    }
+    let k = k.min(n - k);
+    (0..k).fold(BigUint::from(1u8), |r, i| r * (n - i) / (i + 1))
+}
+
+/// Number of multisets of size `r` drawn from `k` items.
+fn multichoose(k: usize, r: usize) -> BigUint {
+    if k == 0 {
+        return BigUint::from((r == 0) as u8);
+    }
+    binomial(k + r - 1, r)
+}
+
+/// Combination number `idx` of `r` out of `n` items, as increasing item
+/// indices, decoded in the combinatorial number system.
+fn combination(mut idx: BigUint, n: usize, r: usize) -> Vec<usize> {
+    let mut out = vec![0; r];
+    let mut hi = n;
+    for j in (1..=r).rev() {
+        // Largest c < hi with C(c, j) <= idx.
+        let (mut lo, mut top) = (j - 1, hi);
+        while top - lo > 1 {
+            let mid = lo + (top - lo) / 2;
+            if binomial(mid, j) <= idx {
+                lo = mid;
+            } else {
+                top = mid;
+            }
+        }
+        idx -= binomial(lo, j);
+        out[j - 1] = lo;
+        hi = lo;
+    }
+    out
+}
+
+/// Multiset number `idx` of size `r` over `k` items, as non-decreasing item
+/// indices. Multisets correspond to `r`-combinations of `k + r - 1` items (stars
+/// and bars).
+fn multiset(idx: BigUint, k: usize, r: usize) -> Vec<usize> {
+    let mut out = combination(idx, k + r - 1, r);
+    for (j, c) in out.iter_mut().enumerate() {
+        *c -= j;
+    }
+    out
+}
+
+/// Lengths of the runs of equal values in `v`.
+fn runs(v: &[usize]) -> Vec<usize> {
+    v.chunk_by(|a, b| a == b).map(<[usize]>::len).collect()
+}
+
+/// `v % m` as a `usize`, for `m` that fits one.
+fn rem(v: &BigUint, m: usize) -> usize {
+    usize::try_from(v % m).unwrap()
+}
+
+/// Ways to write `total` as an ordered sum of `parts` non-negative terms.
+fn compositions(total: usize, parts: usize) -> Vec<Vec<usize>> {
+    if parts == 0 {
+        return if total == 0 { vec![vec![]] } else { vec![] };
+    }
+    (0..=total)
+        .flat_map(|first| {
+            compositions(total - first, parts - 1)
+                .into_iter()
+                .map(move |mut rest| {
+                    rest.insert(0, first);
+                    rest
+                })
+        })
+        .collect()
+}
+
+/// Ways to write `total` as a sum of positive terms, largest first.
+fn partitions(total: usize, max: usize) -> Vec<Vec<usize>> {
+    if total == 0 {
+        return vec![vec![]];
+    }
+    (1..=max.min(total))
+        .rev()
+        .flat_map(|first| {
+            partitions(total - first, first)
+                .into_iter()
+                .map(move |mut rest| {
+                    rest.insert(0, first);
+                    rest
+                })
+        })
+        .collect()
+}
+
+/// Ways to split the run lengths `rest` among files: multisets of non-zero
+/// shapes (inputs per run) that sum to `rest`, each as non-increasing shapes.
+fn shapes(rest: &[usize], max: Option<&[usize]>) -> Vec<Vec<Vec<usize>>> {
+    if rest.iter().all(|&l| l == 0) {
+        return vec![vec![]];
+    }
+    rest.iter()
+        .map(|&l| 0..=l)
+        .multi_cartesian_product()
+        .filter(|shape| shape.iter().any(|&c| c > 0) && max.is_none_or(|max| **shape <= *max))
+        .flat_map(|shape| {
+            let rest: Vec<usize> = rest.iter().zip(&shape).map(|(l, c)| l - c).collect();
+            shapes(&rest, Some(&shape))
+                .into_iter()
+                .map(move |mut tail| {
+                    tail.insert(0, shape.clone());
+                    tail
+                })
+        })
+        .collect()
+}
+
+impl<'a> Space<'a> {
+    /// Build the space of `n`-input merges. `sources` carry every per-input
+    /// parameter except `x`/`s`, which are drawn from `ratios`, and are paired
+    /// with their input group.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        n: usize,
+        sources: &[(InputSpec<'a>, usize)],
+        ratios: &'a [(usize, usize)],
+        results: &'a [(usize, usize)],
+        modes: &'a [Mode],
+        resampling: Resampling,
+        finish: Finish,
+        constraints: &'a Constraints,
+    ) -> Self {
+        let mut by_class: BTreeMap<(usize, u64), Vec<File<'a>>> = BTreeMap::new();
+        let mut file_of: HashMap<*const SourceWave, usize> = HashMap::new();
+        for &(src, group) in sources.iter().filter(|(src, _)| !src.window().is_empty()) {
+            let (wave, _) = src.wave;
+            let files = by_class
+                .entry((wave.channels(), wave.sample_rate().to_bits()))
+                .or_default();
+            let f = *file_of.entry(src.wave as *const _).or_insert_with(|| {
+                files.push(File {
+                    sources: Vec::new(),
+                    group,
+                });
+                files.len() - 1
+            });
+            files[f].sources.push(src);
+        }
+        let classes = by_class
+            .into_values()
+            .map(|mut files| {
+                let key = |file: &File| (file.group, file.sources.len());
+                files.sort_by_key(key);
+                let mut buckets: Vec<Range<usize>> = Vec::new();
+                for (i, file) in files.iter().enumerate() {
+                    match buckets.last_mut() {
+                        Some(b) if key(&files[b.start]) == key(file) => b.end = i + 1,
+                        _ => buckets.push(i..i + 1),
+                    }
+                }
+                Class { files, buckets }
+            })
+            .collect();
+
+        let (commutative, ordered): (Vec<Mode>, Vec<Mode>) =
+            modes.iter().partition(|m| m.is_commutative());
+
+        Space {
+            n,
+            classes,
+            ratios,
+            results,
+            ordered,
+            commutative,
+            resampling,
+            finish,
+            constraints,
+        }
+    }
+
+    /// Ratio assignments that pass [`ratios_ok`], as per-input indices into
+    /// `ratios` and an index into `results`. With `sorted`, only assignments
+    /// whose per-input indices are non-decreasing.
+    fn ratio_assignments(&self, sorted: bool) -> impl Iterator<Item = (Vec<usize>, usize)> + '_ {
+        let tuples: Box<dyn Iterator<Item = Vec<usize>> + Send> = if sorted {
+            Box::new((0..self.ratios.len()).combinations_with_replacement(self.n))
+        } else {
+            Box::new(itertools::repeat_n(0..self.ratios.len(), self.n).multi_cartesian_product())
+        };
+        tuples
+            .cartesian_product(0..self.results.len())
+            .filter(|(t, r)| {
+                let pairs = t.iter().map(|&d| self.ratios[d]);
+                ratios_ok(pairs.chain([self.results[*r]]))
+            })
+    }
+
+    /// Draw patterns of `class` for inputs in runs of the given lengths that
+    /// use at least the minimum number of distinct files and meet the group
+    /// rule: every way to split the runs among files, with the files of each
+    /// shape spread over buckets.
+    fn draws(&self, class: &Class, runs: &[usize]) -> Vec<Vec<Draw>> {
+        let buckets = class.buckets.len();
+        shapes(runs, None)
+            .into_iter()
+            .filter(|files| files.len() >= self.constraints.min_distinct)
+            .flat_map(|files| {
+                // Equal shapes are adjacent; files of one shape are unordered.
+                files
+                    .chunk_by(|a, b| a == b)
+                    .map(|same| {
+                        compositions(same.len(), buckets)
+                            .into_iter()
+                            .map(|counts| {
+                                counts
+                                    .into_iter()
+                                    .enumerate()
+                                    .filter(|&(_, count)| count > 0)
+                                    .map(|(bucket, count)| Draw {
+                                        bucket,
+                                        count,
+                                        shape: same[0].clone(),
+                                    })
+                                    .collect::<Vec<_>>()
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                    .multi_cartesian_product()
+                    .map(|draws| draws.concat())
+                    .collect::<Vec<_>>()
+            })
+            .filter(|draws| {
+                (0..buckets).all(|b| {
+                    let used: usize = draws
+                        .iter()
+                        .filter(|d| d.bucket == b)
+                        .map(|d| d.count)
+                        .sum();
+                    used <= class.buckets[b].len()
+                })
+            })
+            .filter(|draws| {
+                let groups: Vec<usize> = draws
+                    .iter()
+                    .flat_map(|d| {
+                        let inputs = d.count * d.shape.iter().sum::<usize>();
+                        std::iter::repeat_n(class.group(d.bucket), inputs)
+                    })
+                    .collect();
+                self.constraints.allows(&groups)
+            })
+            .collect()
+    }
+
+    /// Number of source selections of `class` that follow `draws`: the files
+    /// of every draw are a combination of those its bucket has left, and each
+    /// file's sources in a run are a multiset.
+    fn selections(class: &Class, draws: &[Draw]) -> BigUint {
+        let mut used = vec![0; class.buckets.len()];
+        let mut total = BigUint::from(1u8);
+        for draw in draws {
+            let size = class.buckets[draw.bucket].len();
+            total *= binomial(size - used[draw.bucket], draw.count);
+            used[draw.bucket] += draw.count;
+            let width = class.width(draw.bucket);
+            let per_file: BigUint = draw.shape.iter().map(|&c| multichoose(width, c)).product();
+            total *= per_file.pow(draw.count as u32);
+        }
+        total
+    }
+
+    /// Number of merges in the space, counted without walking it.
+    ///
+    /// [`ratios_ok`] rejects an assignment when all of its pairs, the result
+    /// included, are contracting, or all are expanding. So for each result the
+    /// count is that over all pairs, less that over contracting pairs if the
+    /// result is contracting and likewise for expanding, plus that over pairs
+    /// that are both if the result is both. Over `m` pairs there are `m^n`
+    /// ordered ratio assignments, each with the same source selections. Sorted
+    /// assignments are counted by the lengths of their runs of equal pairs: a
+    /// partition of `n` into `p` parts takes `p` of the `m` pairs and orders
+    /// its distinct parts among them.
+    pub fn len(&self) -> BigUint {
+        let contracting = |(x, s): (usize, usize)| s * 2 > x;
+        let expanding = |(x, s): (usize, usize)| x * 2 > s;
+        let count =
+            |f: &dyn Fn((usize, usize)) -> bool| self.ratios.iter().filter(|&&p| f(p)).count();
+        let all = self.ratios.len();
+        let c = count(&contracting);
+        let e = count(&expanding);
+        let b = count(&|p| contracting(p) && expanding(p));
+        let factorial = |k: usize| (1..=k).map(BigUint::from).product::<BigUint>();
+        let per_runs = |class: &Class, runs: &[usize]| -> BigUint {
+            self.draws(class, runs)
+                .iter()
+                .map(|draws| Self::selections(class, draws))
+                .sum()
+        };
+
+        let mut total = BigUint::ZERO;
+        for class in &self.classes {
+            let ordered = match self.ordered.len() {
+                0 => BigUint::ZERO,
+                modes => per_runs(class, &vec![1; self.n]) * modes,
+            };
+            let commutative: Vec<(usize, BigUint)> = match self.commutative.len() {
+                0 => Vec::new(),
+                modes => partitions(self.n, self.n)
+                    .into_iter()
+                    .map(|runs| {
+                        let arrangements = runs
+                            .chunk_by(|a, b| a == b)
+                            .fold(factorial(runs.len()), |a, same| a / factorial(same.len()));
+                        (runs.len(), arrangements * per_runs(class, &runs) * modes)
+                    })
+                    .collect(),
+            };
+            let merges = |m: usize| {
+                let sorted: BigUint = commutative
+                    .iter()
+                    .map(|(parts, count)| binomial(m, *parts) * count)
+                    .sum();
+                BigUint::from(m).pow(self.n as u32) * &ordered + sorted
+            };
+            for &r in self.results {
+                let mut valid = merges(all);
+                if contracting(r) && expanding(r) {
+                    valid += merges(b);
+                }
+                if contracting(r) {
+                    valid -= merges(c);
+                }
+                if expanding(r) {
+                    valid -= merges(e);
+                }
+                total += valid;
+            }
+        }
+        total
+    }
+
+    /// All non-empty blocks of the space, generated on demand.
+    pub fn blocks(&self) -> impl Iterator<Item = Block<'_, 'a>> + Send + '_ {
+        (0..self.classes.len())
+            .flat_map(move |class| {
+                let ordered = (!self.ordered.is_empty())
+                    .then(|| self.ratio_assignments(false))
+                    .into_iter()
+                    .flatten()
+                    .map(move |(ratios, result)| (class, ratios, result, false));
+                let commutative = (!self.commutative.is_empty())
+                    .then(|| self.ratio_assignments(true))
+                    .into_iter()
+                    .flatten()
+                    .map(move |(ratios, result)| (class, ratios, result, true));
+                ordered.chain(commutative)
+            })
+            .flat_map(move |(class, ratios, result, commutative)| {
+                let (runs, modes) = if commutative {
+                    (runs(&ratios), self.commutative.len())
+                } else {
+                    (vec![1; self.n], self.ordered.len())
+                };
+                let c = &self.classes[class];
+                self.draws(c, &runs).into_iter().map(move |draws| Block {
+                    space: self,
+                    class,
+                    ratios: ratios.clone(),
+                    result,
+                    commutative,
+                    runs: runs.clone(),
+                    len: Self::selections(c, &draws) * modes,
+                    draws,
+                })
+            })
+            .filter(|block| block.len != BigUint::ZERO)
+    }
+
+    /// All merges of the space in chunks, generated on demand.
+    pub fn chunks(&self) -> impl Iterator<Item = Chunk<'_, 'a>> + Send + '_ {
+        self.blocks().flat_map(|block| {
+            let block = Arc::new(block);
+            successors(Some(BigUint::ZERO), |start| Some(start + CHUNK))
+                .take_while({
+                    let len = block.len.clone();
+                    move |start| *start < len
+                })
+                .map(move |start| {
+                    let len = u64::try_from(&block.len - &start).map_or(CHUNK, |l| l.min(CHUNK));
+                    Chunk {
+                        block: block.clone(),
+                        start,
+                        len,
+                    }
+                })
+        })
+    }
+}
+
+impl<'a> Block<'_, 'a> {
+    /// Decode merge `idx` (`< len`) of the block.
+    ///
+    /// Indices are laid out as `mode + modes * sources`. `sources` holds, as
+    /// mixed-radix digits, the files of each draw (a combination of the files
+    /// its bucket has left) followed by the sources of each of those files in
+    /// every run it feeds (a multiset).
+    fn get(&self, idx: &BigUint) -> MergeParams<'a> {
+        let space = self.space;
+        let class = &space.classes[self.class];
+        let modes = if self.commutative {
+            &space.commutative
+        } else {
+            &space.ordered
+        };
+        let mode = modes[rem(idx, modes.len())];
+        let mut rest = idx / modes.len();
+
+        let mut taken: Vec<Vec<usize>> = vec![Vec::new(); class.buckets.len()];
+        let mut files = Vec::with_capacity(self.draws.len());
+        for draw in &self.draws {
+            let bucket = &class.buckets[draw.bucket];
+            let taken = &mut taken[draw.bucket];
+            let left = bucket.len() - taken.len();
+            let radix = binomial(left, draw.count);
+            let picked: Vec<usize> = combination(&rest % &radix, left, draw.count)
+                .into_iter()
+                .map(|mut f| {
+                    // Skip the files earlier draws took.
+                    for &t in taken.iter() {
+                        if t <= f {
+                            f += 1;
+                        }
+                    }
+                    f
+                })
+                .collect();
+            rest /= radix;
+            taken.extend(&picked);
+            taken.sort();
+            files.push(
+                picked
+                    .into_iter()
+                    .map(|f| bucket.start + f)
+                    .collect::<Vec<_>>(),
+            );
+        }
+
+        let mut chosen: Vec<Vec<(usize, usize)>> = vec![Vec::new(); self.runs.len()];
+        for (draw, files) in self.draws.iter().zip(files) {
+            let width = class.width(draw.bucket);
+            for file in files {
+                for (run, &c) in draw.shape.iter().enumerate() {
+                    let radix = multichoose(width, c);
+                    let sources = multiset(&rest % &radix, width, c);
+                    chosen[run].extend(sources.into_iter().map(|src| (file, src)));
+                    rest /= radix;
+                }
+            }
+        }
+        let chosen: Vec<(usize, usize)> = chosen
+            .into_iter()
+            .flat_map(|mut run| {
+                run.sort();
+                run
+            })
+            .collect();
+
+        let (rx, rs) = space.results[self.result];
+        let inputs = chosen
+            .into_iter()
+            .zip(&self.ratios)
+            .map(|((file, src), &ratio)| {
+                let (x, s) = space.ratios[ratio];
+                InputSpec {
+                    x,
+                    s,
+                    ..class.files[file].sources[src]
+                }
+            })
+            .collect();
+
+        let mut params = MergeParams {
+            inputs,
+            rx,
+            rs,
+            mode,
+            resampling: space.resampling,
+            finish: space.finish,
+        };
+        params.canonicalize();
+        params
+    }
+}
+
+impl<'a> Chunk<'_, 'a> {
+    /// The merges of the chunk in order.
+    pub fn merges(&self) -> impl Iterator<Item = MergeParams<'a>> + '_ {
+        (0..self.len).map(|i| self.block.get(&(&self.start + i)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, sync::OnceLock};
+
+    use fundsp::wave::Wave;
+
+    use super::*;
+    use crate::Span;
+
+    const RATIOS: [(usize, usize); 3] = [(1, 1), (1, 2), (2, 1)];
+    const RESULTS: [(usize, usize); 2] = [(1, 1), (2, 1)];
+    const MODES: [Mode; 3] = [Mode::Standard, Mode::Div, Mode::Atan];
+
+    /// Files of different lengths, so each has its own content hash.
+    fn waves(files: usize) -> Vec<SourceWave> {
+        (0..files)
+            .map(|f| {
+                let mut wave = Wave::new(0, 8000.0);
+                wave.push_channel(&vec![0.5; 16 + f]);
+                (wave, OnceLock::new())
+            })
+            .collect()
+    }
+
+    /// `widths[f]` sources of file `f` in group `groups[f]`, told apart by
+    /// their gain.
+    fn sources<'a>(
+        waves: &'a [SourceWave],
+        widths: &[usize],
+        groups: &[usize],
+    ) -> Vec<(InputSpec<'a>, usize)> {
+        let mut sources = Vec::new();
+        for (f, wave) in waves.iter().enumerate() {
+            for _ in 0..widths[f] {
+                let spec = InputSpec {
+                    wave,
+                    path: std::path::Path::new(""),
+                    x: 1,
+                    s: 1,
+                    om: false,
+                    rev: false,
+                    start: Span::Frac(0.0),
+                    len: Span::Frac(1.0),
+                    gain: sources.len() as f32 + 1.0,
+                    filter: None,
+                    pitch: 0,
+                    stretch: 1.0,
+                };
+                sources.push((spec, groups[f]));
+            }
+        }
+        sources
+    }
+
+    fn constraints(min_distinct: usize, group_rule: GroupRule, groups: &[usize]) -> Constraints {
+        let mut groups = groups.to_vec();
+        groups.sort();
+        groups.dedup();
+        Constraints {
+            min_distinct,
+            group_rule,
+            groups,
+        }
+    }
+
+    /// A merge as (mode, result, (ratio, source) per input), with the inputs
+    /// sorted for commutative modes.
+    type Key = (String, usize, Vec<(usize, usize)>);
+
+    fn key(params: &MergeParams) -> Key {
+        let ratio = |x, s| RATIOS.iter().position(|&r| r == (x, s)).unwrap();
+        let mut inputs: Vec<(usize, usize)> = params
+            .inputs
+            .iter()
+            .map(|inp| (ratio(inp.x, inp.s), inp.gain as usize - 1))
+            .collect();
+        if params.mode.is_commutative() {
+            inputs.sort();
+        }
+        let result = RESULTS
+            .iter()
+            .position(|&r| r == (params.rx, params.rs))
+            .unwrap();
+        (params.mode.to_string(), result, inputs)
+    }
+
+    /// Every merge the space should hold, by trying every ordered tuple of
+    /// (ratio, source) pairs and keeping the sorted ones for commutative modes.
+    fn brute_force(
+        sources: &[(InputSpec, usize)],
+        n: usize,
+        constraints: &Constraints,
+    ) -> HashSet<Key> {
+        let file = |src: usize| sources[src].0.wave as *const SourceWave;
+        let items: Vec<(usize, usize)> = (0..RATIOS.len())
+            .flat_map(|r| (0..sources.len()).map(move |src| (r, src)))
+            .collect();
+        let mut merges = HashSet::new();
+        for tuple in itertools::repeat_n(items.iter().copied(), n).multi_cartesian_product() {
+            let files: HashSet<_> = tuple.iter().map(|&(_, src)| file(src)).collect();
+            let groups: Vec<usize> = tuple.iter().map(|&(_, src)| sources[src].1).collect();
+            if files.len() < constraints.min_distinct || !constraints.allows(&groups) {
+                continue;
+            }
+            for (result, &pair) in RESULTS.iter().enumerate() {
+                if !ratios_ok(tuple.iter().map(|&(r, _)| RATIOS[r]).chain([pair])) {
+                    continue;
+                }
+                for mode in MODES {
+                    if mode.is_commutative() && !tuple.is_sorted() {
+                        continue;
+                    }
+                    merges.insert((mode.to_string(), result, tuple.clone()));
+                }
+            }
+        }
+        merges
+    }
+
+    /// Decode every merge of the space, checking that no two are the same.
+    fn walk(space: &Space) -> HashSet<Key> {
+        let mut merges = HashSet::new();
+        for chunk in space.chunks() {
+            for params in chunk.merges() {
+                let key = key(&params);
+                assert!(merges.insert(key.clone()), "decoded twice: {key:?}");
+            }
+        }
+        merges
+    }
+
+    fn space<'a>(
+        n: usize,
+        sources: &[(InputSpec<'a>, usize)],
+        constraints: &'a Constraints,
+    ) -> Space<'a> {
+        Space::new(
+            n,
+            sources,
+            &RATIOS,
+            &RESULTS,
+            &MODES,
+            Resampling::Legacy,
+            Finish::default(),
+            constraints,
+        )
+    }
+
+    #[test]
+    fn binomials() {
+        assert_eq!(binomial(5, 2), BigUint::from(10u8));
+        assert_eq!(binomial(2, 5), BigUint::ZERO);
+        assert_eq!(multichoose(3, 2), BigUint::from(6u8));
+        assert_eq!(multichoose(0, 0), BigUint::from(1u8));
+        assert_eq!(multichoose(0, 1), BigUint::ZERO);
+    }
+
+    #[test]
+    fn multisets_unrank_onto_sorted_tuples() {
+        for k in 1..=4 {
+            for r in 0..=4 {
+                // Decoded in colexicographic order.
+                let mut all: Vec<Vec<usize>> = (0..multichoose(k, r).try_into().unwrap())
+                    .map(|idx: u64| multiset(BigUint::from(idx), k, r))
+                    .collect();
+                all.sort();
+                let expected: Vec<Vec<usize>> = (0..k).combinations_with_replacement(r).collect();
+                assert_eq!(all, expected, "k={k} r={r}");
+            }
+        }
+    }
+
+    #[test]
+    fn len_matches_brute_force() {
+        let waves = waves(3);
+        for widths in [[1, 1, 1], [2, 1, 3]] {
+            let sources = sources(&waves, &widths, &[0; 3]);
+            for n in 2..=3 {
+                for min_distinct in 1..=n {
+                    let constraints = constraints(min_distinct, GroupRule::Any, &[0]);
+                    let space = space(n, &sources, &constraints);
+                    let expected = brute_force(&sources, n, &constraints);
+                    let case = format!("widths={widths:?} n={n} min_distinct={min_distinct}");
+                    assert_eq!(space.len(), BigUint::from(expected.len()), "{case}");
+                    let blocks: BigUint = space.blocks().map(|b| b.len).sum();
+                    assert_eq!(blocks, space.len(), "{case}");
+                    assert_eq!(walk(&space), expected, "{case}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn group_rules_match_brute_force() {
+        let waves = waves(4);
+        let groups = [0, 1, 1, 2];
+        let sources = sources(&waves, &[1, 2, 1, 1], &groups);
+        for rule in [
+            GroupRule::Any,
+            GroupRule::Each,
+            GroupRule::AtLeast(2),
+            GroupRule::AtLeast(3),
+        ] {
+            for n in 2..=3 {
+                for min_distinct in [1, 2] {
+                    let constraints = constraints(min_distinct, rule, &groups);
+                    let case = format!("{rule:?} n={n} min_distinct={min_distinct}");
+                    let expected = brute_force(&sources, n, &constraints);
+                    assert_eq!(constraints.allows_size(n), !expected.is_empty(), "{case}");
+                    let space = space(n, &sources, &constraints);
+                    assert_eq!(space.len(), BigUint::from(expected.len()), "{case}");
+                    assert_eq!(walk(&space), expected, "{case}");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,63 @@
+//! Cooperative cancellation.
+//!
+//! Signals and exhausted budgets only record why the run should stop; workers
+//! check before starting each merge, so merges in flight still finish and write
+//! their output, and the run ends with a report instead of mid-write.
+
+use std::{
+    fmt,
+    sync::{Arc, OnceLock},
+};
+
+/// Why a run stopped early.
+#[derive(Debug)]
+pub enum Reason {
+    /// SIGINT, SIGTERM or SIGHUP.
+    Signal,
+    /// A run budget (named) was used up.
+    Budget(&'static str),
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::Signal => write!(f, "interrupted"),
+            Reason::Budget(name) => write!(f, "{name} budget reached"),
+        }
+    }
+}
+
+/// A stop request shared by the whole run; the first request wins.
+#[derive(Default)]
+pub struct Stop {
+    reason: OnceLock<Reason>,
+}
+
+impl Stop {
+    /// Ask the run to stop.
+    pub fn request(&self, reason: Reason) {
+        let _ = self.reason.set(reason);
+    }
+
+    /// Whether a stop has been requested.
+    pub fn requested(&self) -> bool {
+        self.reason.get().is_some()
+    }
+
+    pub fn reason(&self) -> Option<&Reason> {
+        self.reason.get()
+    }
+
+    /// Request a stop on SIGINT/SIGTERM/SIGHUP. A second signal, or one after a
+    /// budget stop, exits at once.
+    pub fn on_signal(self: &Arc<Self>) -> Result<(), ctrlc::Error> {
+        let stop = self.clone();
+        ctrlc::set_handler(move || {
+            if stop.reason.set(Reason::Signal).is_err() {
+                eprintln!("\nstopping now");
+                std::process::exit(130);
+            }
+            eprintln!("\ninterrupted; finishing merges in progress (again to quit now)");
+        })
+    }
+}
@@ -0,0 +1,78 @@
+//! Time stretching without pitch change (WSOLA).
+//!
+//! Output frames are laid down at a fixed hop and overlap-added under a Hann
+//! window. Each frame is read from around its nominal input position, shifted
+//! within a small tolerance to the offset whose waveform best continues the
+//! previous frame, which keeps periodic material phase-coherent.
+
+use std::f64::consts::PI;
+
+/// Analysis/synthesis frame length in seconds.
+const FRAME_SECONDS: f64 = 0.04;
+
+/// Sample of `input` at `i`, with silence outside it.
+fn at(input: &[f32], i: isize) -> f32 {
+    if i < 0 {
+        0.0
+    } else {
+        input.get(i as usize).copied().unwrap_or(0.0)
+    }
+}
+
+/// Stretch `input` to `factor` times its duration, keeping its pitch.
+pub fn stretch(input: &[f32], factor: f64, sample_rate: f64) -> Vec<f32> {
+    if factor == 1.0 || input.is_empty() {
+        return input.to_vec();
+    }
+    let frame = ((FRAME_SECONDS * sample_rate) as usize).max(16) & !1;
+    let hop = frame / 2;
+    let tolerance = (frame / 4) as isize;
+    let window: Vec<f32> = (0..frame)
+        .map(|i| (0.5 - 0.5 * (2.0 * PI * i as f64 / frame as f64).cos()) as f32)
+        .collect();
+
+    let out_len = (input.len() as f64 * factor).round() as usize;
+    let mut out = vec![0.0f32; out_len + frame];
+    let mut weight = vec![0.0f32; out_len + frame];
+
+    // Input position the previous frame was read from.
+    let mut prev: isize = 0;
+    for k in 0..=out_len / hop {
+        let nominal = (k as f64 * hop as f64 / factor).round() as isize;
+        let pos = if k == 0 {
+            0
+        } else {
+            // The natural continuation of the previous frame is `prev + hop`; pick
+            // the candidate around `nominal` that correlates best with it over
+            // the overlapping half.
+            let target = prev + hop as isize;
+            (-tolerance..=tolerance)
+                .map(|d| {
+                    let c = nominal + d;
+                    let score: f32 = (0..hop as isize)
+                        .step_by(2)
+                        .map(|j| at(input, c + j) * at(input, target + j))
+                        .sum();
+                    (c, score)
+                })
+                .fold((nominal, f32::NEG_INFINITY), |best, cur| {
+                    if cur.1 > best.1 { cur } else { best }
+                })
+                .0
+        };
+        let base = k * hop;
+        for (j, &w) in window.iter().enumerate() {
+            out[base + j] += at(input, pos + j as isize) * w;
+            weight[base + j] += w;
+        }
+        prev = pos;
+    }
+
+    out.truncate(out_len);
+    for (o, &w) in out.iter_mut().zip(&weight) {
+        if w > 1e-3 {
+            *o /= w;
+        }
+    }
+    out
+}
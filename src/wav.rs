@@ -0,0 +1,275 @@
+//! WAV encoding.
+//!
+//! Integer formats are TPDF-dithered: the sum of two independent uniform values
+//! of one LSB each is added before rounding, which turns quantisation error into
+//! benign white noise. The dither is seeded per output, so a recipe always
+//! encodes to the same bytes.
+//!
+//! The recipe travels inside the file, after the audio: a LIST/INFO chunk with
+//! a summary most tools show, and an iXML chunk whose `USER` element holds every
+//! field as `KEY=value` lines, which [`read_recipe`] parses back. Looping
+//! outputs also get a `smpl` chunk with their loop and root note for samplers.
+
+use fundsp::wave::Wave;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+/// Sample encoding of the output files.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum Format {
+    /// 16-bit integer PCM.
+    Pcm16,
+    /// 24-bit integer PCM.
+    Pcm24,
+    /// 32-bit IEEE float.
+    Float32,
+    /// 16-bit for outputs much longer than their inputs, 32-bit float otherwise.
+    Auto,
+}
+
+impl Format {
+    /// Bits per sample; `Auto` must be resolved first.
+    pub fn bits(self) -> u32 {
+        match self {
+            Format::Pcm16 => 16,
+            Format::Pcm24 => 24,
+            Format::Float32 => 32,
+            Format::Auto => unreachable!("auto format is resolved per output"),
+        }
+    }
+}
+
+/// Sampler metadata of a looping output.
+pub struct Sampler {
+    /// Root note as a fractional MIDI note number.
+    pub root_note: f64,
+    /// First and last sample frame of the loop.
+    pub loop_start: u32,
+    pub loop_end: u32,
+}
+
+/// `smpl` chunk body with one forward loop that plays indefinitely.
+fn smpl(sampler: &Sampler, sample_rate: u32) -> Vec<u8> {
+    // The unity note is whole; the pitch fraction is the rest of a semitone
+    // in units of 2^-32.
+    let note = sampler.root_note.clamp(0.0, 127.0);
+    let fraction = ((note - note.floor()) * 4_294_967_296.0) as u32;
+    let fields = [
+        0, // manufacturer
+        0, // product
+        (1e9 / sample_rate as f64).round() as u32,
+        note.floor() as u32,
+        fraction,
+        0, // SMPTE format
+        0, // SMPTE offset
+        1, // loops
+        0, // sampler data
+        0, // cue point
+        0, // loop type: forward
+        sampler.loop_start,
+        sampler.loop_end,
+        0, // fraction
+        0, // play count: infinite
+    ];
+    fields.iter().flat_map(|v: &u32| v.to_le_bytes()).collect()
+}
+
+/// Interleaved samples of `wave` as dithered `bits`-bit integers, seeding the
+/// dither with `seed`.
+pub fn quantise(wave: &Wave, bits: u32, seed: [u8; 32]) -> Vec<i32> {
+    let full_scale = ((1 << (bits - 1)) - 1) as f32;
+    let mut rng = ChaCha8Rng::from_seed(seed);
+    let mut out = Vec::with_capacity(wave.channels() * wave.len());
+    for i in 0..wave.len() {
+        for ch in 0..wave.channels() {
+            let tpdf = rng.random::<f32>() - rng.random::<f32>();
+            let v = (wave.at(ch, i).clamp(-1.0, 1.0) * full_scale + tpdf).round();
+            out.push(v.clamp(-full_scale - 1.0, full_scale) as i32);
+        }
+    }
+    out
+}
+
+/// Append a RIFF chunk, padded to an even length.
+fn chunk(out: &mut Vec<u8>, id: &[u8; 4], body: &[u8]) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(body);
+    if body.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+/// LIST/INFO chunk body: name (the hash), software and a one-line comment.
+fn info(recipe: &[(&str, String)]) -> Vec<u8> {
+    let field = |key: &str| {
+        recipe
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v.as_str())
+    };
+    let comment: Vec<String> = recipe
+        .iter()
+        .filter(|(k, _)| !matches!(*k, "GENERATOR" | "HASH"))
+        .map(|(k, v)| format!("{k}={v}"))
+        .collect();
+    let mut body = b"INFO".to_vec();
+    for (id, text) in [
+        (b"INAM", field("HASH").unwrap_or_default().to_owned()),
+        (b"ISFT", field("GENERATOR").unwrap_or_default().to_owned()),
+        (b"ICMT", comment.join("; ")),
+    ] {
+        let mut text = text.into_bytes();
+        text.push(0);
+        chunk(&mut body, id, &text);
+    }
+    body
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn xml_unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// iXML chunk body with the recipe in the `USER` element.
+fn ixml(recipe: &[(&str, String)]) -> Vec<u8> {
+    let user: String = recipe
+        .iter()
+        .map(|(k, v)| format!("{k}={}\n", xml_escape(v)))
+        .collect();
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<BWFXML>\n\
+         <IXML_VERSION>2.10</IXML_VERSION>\n<PROJECT>{}</PROJECT>\n\
+         <USER>\n{user}</USER>\n</BWFXML>\n",
+        env!("CARGO_PKG_NAME"),
+    )
+    .into_bytes()
+}
+
+/// Read the recipe fields back from a WAV file written by [`encode`], or `None`
+/// if it has no recipe.
+pub fn read_recipe(bytes: &[u8]) -> Option<Vec<(String, String)>> {
+    if bytes.len() < 12 || &bytes[..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return None;
+    }
+    let mut pos = 12;
+    while pos + 8 <= bytes.len() {
+        let id = &bytes[pos..pos + 4];
+        let len = u32::from_le_bytes(bytes[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        let body = bytes.get(pos + 8..pos + 8 + len)?;
+        if id == b"iXML" {
+            let xml = std::str::from_utf8(body).ok()?;
+            let user = xml.split_once("<USER>")?.1.split_once("</USER>")?.0;
+            return Some(
+                user.lines()
+                    .filter_map(|line| line.split_once('='))
+                    .map(|(k, v)| (k.to_owned(), xml_unescape(v)))
+                    .collect(),
+            );
+        }
+        pos += 8 + len + len % 2;
+    }
+    None
+}
+
+/// Encode `wave` as a WAV file in a concrete (non-`Auto`) `format`, seeding the
+/// dither with `seed`, with the `recipe` fields and any `sampler` metadata
+/// embedded.
+pub fn encode(
+    wave: &Wave,
+    format: Format,
+    seed: [u8; 32],
+    recipe: &[(&str, String)],
+    sampler: Option<&Sampler>,
+) -> Vec<u8> {
+    let channels = wave.channels();
+    let sample_bytes = format.bits() as usize / 8;
+    let data_len = sample_bytes * channels * wave.len();
+    let sample_rate = wave.sample_rate().round() as u32;
+    // 1 = WAVE_FORMAT_PCM, 3 = WAVE_FORMAT_IEEE_FLOAT.
+    let tag: u16 = if format == Format::Float32 { 3 } else { 1 };
+
+    let mut out = Vec::with_capacity(44 + data_len);
+    out.extend_from_slice(b"RIFF");
+    // Patched once all chunks are in.
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(b"WAVE");
+    out.extend_from_slice(b"fmt ");
+    out.extend_from_slice(&16u32.to_le_bytes());
+    out.extend_from_slice(&tag.to_le_bytes());
+    out.extend_from_slice(&(channels as u16).to_le_bytes());
+    out.extend_from_slice(&sample_rate.to_le_bytes());
+    out.extend_from_slice(&(sample_rate * (channels * sample_bytes) as u32).to_le_bytes());
+    out.extend_from_slice(&((channels * sample_bytes) as u16).to_le_bytes());
+    out.extend_from_slice(&(sample_bytes as u16 * 8).to_le_bytes());
+    // Formats other than PCM must state their length in sample frames.
+    if tag != 1 {
+        chunk(&mut out, b"fact", &(wave.len() as u32).to_le_bytes());
+    }
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&(data_len as u32).to_le_bytes());
+
+    if format == Format::Float32 {
+        for i in 0..wave.len() {
+            for ch in 0..channels {
+                out.extend_from_slice(&wave.at(ch, i).to_le_bytes());
+            }
+        }
+    } else {
+        for v in quantise(wave, format.bits(), seed) {
+            out.extend_from_slice(&v.to_le_bytes()[..sample_bytes]);
+        }
+    }
+    if data_len % 2 == 1 {
+        out.push(0);
+    }
+
+    if let Some(sampler) = sampler {
+        chunk(&mut out, b"smpl", &smpl(sampler, sample_rate));
+    }
+    chunk(&mut out, b"LIST", &info(recipe));
+    chunk(&mut out, b"iXML", &ixml(recipe));
+    let riff_len = (out.len() - 8) as u32;
+    out[4..8].copy_from_slice(&riff_len.to_le_bytes());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recipe_round_trips() {
+        let recipe = [
+            ("hash", "0123abcd".to_owned()),
+            ("source", "/tmp/drums & bass/<kick>.wav".to_owned()),
+            ("source", "/tmp/ünïcødé/キック.wav".to_owned()),
+            ("mode", "a&lt;b &amp; c>d".to_owned()),
+            ("empty", String::new()),
+        ];
+        let mut wave = Wave::new(0, 8000.0);
+        // An odd-length data chunk, so the chunks after it start padded.
+        wave.push_channel(&[0.25, -0.5, 1.0]);
+        let sampler = Sampler {
+            root_note: 60.0,
+            loop_start: 0,
+            loop_end: 2,
+        };
+        for format in [Format::Pcm16, Format::Pcm24, Format::Float32] {
+            for sampler in [None, Some(&sampler)] {
+                let bytes = encode(&wave, format, [0; 32], &recipe, sampler);
+                let read = read_recipe(&bytes).unwrap();
+                let read: Vec<_> = read.iter().map(|(k, v)| (k.as_str(), v.clone())).collect();
+                assert_eq!(read, recipe, "{format:?}");
+            }
+        }
+        assert_eq!(read_recipe(b"RIFF\0\0\0\0WAVE"), None);
+    }
+}